@@ -0,0 +1,29 @@
+//! # D-Bus interface proxies for: `org.freedesktop.Accounts` and `org.freedesktop.Accounts.User`
+//!
+//! Only the parts of AccountsService that Soteria uses to show who an identity is.
+
+use zbus::{proxy, zvariant::OwnedObjectPath};
+
+#[proxy(
+    interface = "org.freedesktop.Accounts",
+    default_service = "org.freedesktop.Accounts",
+    default_path = "/org/freedesktop/Accounts"
+)]
+pub trait Accounts {
+    /// FindUserById method
+    fn find_user_by_id(&self, id: i64) -> zbus::Result<OwnedObjectPath>;
+}
+
+#[proxy(
+    interface = "org.freedesktop.Accounts.User",
+    default_service = "org.freedesktop.Accounts"
+)]
+pub trait User {
+    /// IconFile property
+    #[zbus(property)]
+    fn icon_file(&self) -> zbus::Result<String>;
+
+    /// RealName property
+    #[zbus(property)]
+    fn real_name(&self) -> zbus::Result<String>;
+}
@@ -0,0 +1,116 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use gettextrs::gettext;
+
+use crate::authority::{AuthorityProxy, Result};
+
+/// How polkit treats a subject that no rule explicitly matched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImplicitAuthorization {
+    NotAuthorized,
+    AuthenticationRequired,
+    AdministratorAuthenticationRequired,
+    AuthenticationRequiredRetained,
+    AdministratorAuthenticationRequiredRetained,
+    Authorized,
+    /// A value added after this agent was written.
+    Unknown,
+}
+
+impl From<u32> for ImplicitAuthorization {
+    fn from(value: u32) -> Self {
+        match value {
+            0 => Self::NotAuthorized,
+            1 => Self::AuthenticationRequired,
+            2 => Self::AdministratorAuthenticationRequired,
+            3 => Self::AuthenticationRequiredRetained,
+            4 => Self::AdministratorAuthenticationRequiredRetained,
+            5 => Self::Authorized,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+impl ImplicitAuthorization {
+    /// What the user has to do for the action, if it asks for authentication at all.
+    pub fn description(&self) -> Option<String> {
+        match self {
+            Self::AuthenticationRequired => Some(gettext("Authentication every time")),
+            Self::AdministratorAuthenticationRequired => {
+                Some(gettext("Administrator authentication every time"))
+            }
+            Self::AuthenticationRequiredRetained => {
+                Some(gettext("Authentication, remembered for a short while"))
+            }
+            Self::AdministratorAuthenticationRequiredRetained => Some(gettext(
+                "Administrator authentication, remembered for a short while",
+            )),
+            Self::NotAuthorized | Self::Authorized | Self::Unknown => None,
+        }
+    }
+}
+
+/// An action as described by `EnumerateActions`, in the locale we registered with. Only the parts
+/// the dialog shows are kept.
+#[derive(Debug, Clone)]
+pub struct ActionDescription {
+    pub action_id: String,
+    pub description: String,
+    pub vendor_name: String,
+    pub vendor_url: String,
+    pub icon_name: String,
+    /// What polkit asks of the active session we registered for, unless a rule says otherwise.
+    pub implicit_active: ImplicitAuthorization,
+}
+
+/// Every action polkit knows about, shared between the agent and the task that keeps it fresh.
+#[derive(Debug, Clone, Default)]
+pub struct ActionCache {
+    actions: Arc<RwLock<HashMap<String, ActionDescription>>>,
+}
+
+impl ActionCache {
+    /// Replaces the cache with what polkit currently reports.
+    pub async fn load(&self, proxy: &AuthorityProxy<'_>, locale: &str) -> Result<()> {
+        let actions: HashMap<String, ActionDescription> = proxy
+            .enumerate_actions(locale)
+            .await?
+            .into_iter()
+            .map(
+                |(
+                    action_id,
+                    description,
+                    _message,
+                    vendor_name,
+                    vendor_url,
+                    icon_name,
+                    _implicit_any,
+                    _implicit_inactive,
+                    implicit_active,
+                    _annotations,
+                )| {
+                    let action = ActionDescription {
+                        action_id,
+                        description,
+                        vendor_name,
+                        vendor_url,
+                        icon_name,
+                        implicit_active: implicit_active.into(),
+                    };
+                    (action.action_id.clone(), action)
+                },
+            )
+            .collect();
+
+        tracing::debug!("loaded {} action descriptions", actions.len());
+        *self.actions.write().unwrap() = actions;
+        Ok(())
+    }
+
+    pub fn get(&self, action_id: &str) -> Option<ActionDescription> {
+        self.actions.read().unwrap().get(action_id).cloned()
+    }
+}
@@ -70,6 +70,19 @@ impl<'a> Identity<'a> {
     pub fn get_details(&self) -> &HashMap<String, Value<'a>> {
         &self.details
     }
+
+    /// Reads a numeric detail like `uid` or `gid`, accepting any integer type that fits.
+    pub fn get_id(&self, key: &str) -> Option<u32> {
+        match self.details.get(key)? {
+            Value::U32(id) => Some(*id),
+            Value::I32(id) => u32::try_from(*id).ok(),
+            Value::U64(id) => u32::try_from(*id).ok(),
+            Value::I64(id) => u32::try_from(*id).ok(),
+            Value::U16(id) => Some((*id).into()),
+            Value::I16(id) => u32::try_from(*id).ok(),
+            _ => None,
+        }
+    }
 }
 
 impl std::fmt::Display for Identity<'_> {
@@ -1,4 +1,8 @@
-use std::path::Path;
+use std::{
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use eyre::Result;
 use figment::{
@@ -11,6 +15,24 @@ use serde::{Deserialize, Serialize};
 pub struct SystemConfig {
     helper_path: String,
     socket_path: String,
+    /// Use the helper even if it is not a root owned setuid binary. Only meant for development, and
+    /// only honoured in a system config file that only root can change.
+    #[serde(default)]
+    allow_unsafe_helper: bool,
+    /// How many failed attempts a request gets before it is cancelled, 0 allows any number.
+    #[serde(default = "default_max_attempts")]
+    max_attempts: u32,
+    /// Seconds a request may go unanswered before it is cancelled, 0 keeps it open forever.
+    #[serde(default = "default_idle_timeout")]
+    idle_timeout: u64,
+}
+
+fn default_max_attempts() -> u32 {
+    3
+}
+
+fn default_idle_timeout() -> u64 {
+    300
 }
 
 impl SystemConfig {
@@ -20,6 +42,8 @@ impl SystemConfig {
         let xdg_config_home = std::env::var_os("XDG_CONFIG_HOME")
             .or_else(|| std::env::var_os("HOME").map(|h| Path::new(&h).join(".config").into()));
         let xdg_path = xdg_config_home.map(|c| Path::new(&c).join("soteria/config.toml"));
+        // The system config file that was used, if any.
+        let mut system_path: Option<PathBuf> = None;
 
         if xdg_path.as_ref().is_some_and(|p| p.exists()) {
             let path = xdg_path.unwrap();
@@ -29,17 +53,37 @@ impl SystemConfig {
         // Prioritize configuration in local, as semantically that is the users config
         else if Path::new("/usr/local/etc/soteria/config.toml").exists() {
             fig = fig.merge(Toml::file_exact("/usr/local/etc/soteria/config.toml"));
+            system_path = Some("/usr/local/etc/soteria/config.toml".into());
             tracing::info!("using configuration file found at /usr/local/etc/soteria/config.toml");
         // Try the configuration location of the distro
         } else if Path::new("/etc/soteria/config.toml").exists() {
             fig = fig.merge(Toml::file_exact("/etc/soteria/config.toml"));
+            system_path = Some("/etc/soteria/config.toml".into());
             tracing::info!("using configuration file found at /etc/soteria/config.toml");
         // Fall back to default
         } else {
             fig = fig.merge(Serialized::defaults(Self::default()));
             tracing::info!("no configuration file found, using default configuration instead");
         }
-        Ok(fig.extract()?)
+
+        let mut config: Self = fig.extract()?;
+        // Whoever can write the config can also point helper_path at their own program, so the
+        // override only counts where only root could have set it.
+        if config.allow_unsafe_helper && !system_path.as_deref().is_some_and(root_only) {
+            tracing::warn!(
+                "ignoring allow_unsafe_helper, it is only honoured in /etc/soteria/config.toml or /usr/local/etc/soteria/config.toml when only root can change them"
+            );
+            config.allow_unsafe_helper = false;
+        }
+        // Lets the integration tests run a fake helper. Never part of a build that gets installed,
+        // the session's environment is as easy to change as the user's config.
+        if cfg!(feature = "test-helper")
+            && std::env::var_os("SOTERIA_ALLOW_UNSAFE_HELPER").is_some()
+        {
+            tracing::warn!("SOTERIA_ALLOW_UNSAFE_HELPER is set, not verifying the helper");
+            config.allow_unsafe_helper = true;
+        }
+        Ok(config)
     }
 
     pub fn get_helper_path(&self) -> &str {
@@ -49,6 +93,23 @@ impl SystemConfig {
     pub fn get_socket_path(&self) -> &str {
         &self.socket_path
     }
+
+    pub fn get_allow_unsafe_helper(&self) -> bool {
+        self.allow_unsafe_helper
+    }
+
+    pub fn get_max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    pub fn get_idle_timeout(&self) -> Option<Duration> {
+        (self.idle_timeout != 0).then(|| Duration::from_secs(self.idle_timeout))
+    }
+}
+
+/// Whether `path` is owned by root and can't be written by anyone else.
+fn root_only(path: &Path) -> bool {
+    std::fs::metadata(path).is_ok_and(|m| m.uid() == 0 && m.mode() & 0o022 == 0)
 }
 
 impl Default for SystemConfig {
@@ -56,6 +117,9 @@ impl Default for SystemConfig {
         Self {
             helper_path: env!("POLKIT_AGENT_HELPER_PATH").into(),
             socket_path: env!("POLKIT_AGENT_SOCKET_PATH").into(),
+            allow_unsafe_helper: false,
+            max_attempts: default_max_attempts(),
+            idle_timeout: default_idle_timeout(),
         }
     }
 }
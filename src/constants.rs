@@ -1 +1,4 @@
 pub const SELF_OBJECT_PATH: &str = "/org/freedesktop/PolicyKit1/AuthenticationAgent";
+pub const POLKIT_BUS_NAME: &str = "org.freedesktop.PolicyKit1";
+pub const EXTERNAL_BUS_NAME: &str = "gay.vaskel.Soteria";
+pub const EXTERNAL_OBJECT_PATH: &str = "/gay/vaskel/Soteria";
@@ -1,202 +1,900 @@
-use gettextrs::gettext;
-use std::{collections::HashMap, process::Stdio};
-use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-    net::UnixStream,
-    process,
-    sync::mpsc,
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
 };
-use zbus::{interface, zvariant::Value};
+use tokio::sync::mpsc;
+use zbus::{
+    Connection,
+    fdo::DBusProxy,
+    interface,
+    message::Header,
+    names::{BusName, WellKnownName},
+};
+use zeroize::Zeroizing;
 
 use crate::{
+    actions::{ActionCache, ActionDescription},
     authority::{Identity, PolkitError, Result},
     config::SystemConfig,
-    events::{AuthenticationAgentEvent, AuthenticationUserEvent},
+    constants,
+    events::{AuthenticationAgentEvent, AuthenticationUserEvent, RequestDetails},
+    failure::FailureReason,
+    frontend::FrontendProxy,
+    helper::{self, HelperBackend, HelperConnection},
+    identity::UserIdentity,
+    lockout::Lockout,
+    password_change::SecretPrompt,
+    protocol::HelperMessage,
+    session::{Session, SessionManager},
 };
 
 #[derive(Debug)]
 pub struct AuthenticationAgent {
-    config: SystemConfig,
-    sender: mpsc::Sender<AuthenticationAgentEvent>,
-    receiver: mpsc::Receiver<AuthenticationUserEvent>,
+    backend: Box<dyn HelperBackend>,
+    actions: ActionCache,
+    frontend: FrontendProxy,
+    sessions: SessionManager,
+    max_attempts: u32,
 }
 
 impl AuthenticationAgent {
     pub fn new(
-        sender: mpsc::Sender<AuthenticationAgentEvent>,
+        frontend: FrontendProxy,
         receiver: mpsc::Receiver<AuthenticationUserEvent>,
         config: SystemConfig,
+        actions: ActionCache,
     ) -> Self {
-        Self {
-            sender,
+        Self::with_backend(
+            frontend,
             receiver,
-            config,
+            helper::from_config(&config),
+            config.get_max_attempts(),
+            config.get_idle_timeout(),
+            actions,
+        )
+    }
+
+    fn with_backend(
+        frontend: FrontendProxy,
+        receiver: mpsc::Receiver<AuthenticationUserEvent>,
+        backend: Box<dyn HelperBackend>,
+        max_attempts: u32,
+        idle_timeout: Option<Duration>,
+        actions: ActionCache,
+    ) -> Self {
+        Self {
+            actions,
+            sessions: SessionManager::new(receiver, frontend.clone(), idle_timeout),
+            frontend,
+            backend,
+            max_attempts,
+        }
+    }
+
+    /// Rejects calls that don't come from polkitd. Any local process can send to our unique name,
+    /// and could otherwise pop up a fake password prompt or cancel a real one.
+    async fn check_caller(
+        &self,
+        header: &Header<'_>,
+        connection: &Connection,
+        method: &str,
+    ) -> Result<()> {
+        let owner = DBusProxy::new(connection)
+            .await?
+            .get_name_owner(BusName::from(WellKnownName::from_static_str_unchecked(
+                constants::POLKIT_BUS_NAME,
+            )))
+            .await
+            .map_err(zbus::Error::from)?;
+
+        match header.sender() {
+            Some(sender) if sender.as_str() == owner.as_str() => Ok(()),
+            sender => {
+                tracing::warn!(
+                    "rejected {} from {:?}, only polkit ({}) may call the agent",
+                    method,
+                    sender.map(|s| s.as_str()),
+                    owner.as_str()
+                );
+                Err(PolkitError::NotAuthorized(format!(
+                    "Only {} may call {method}.",
+                    constants::POLKIT_BUS_NAME
+                )))
+            }
         }
     }
+
+    /// Tells the frontend about a lockout if `message` announces one, returning whether it did.
+    async fn report_lockout(&self, cookie: &str, message: &str) -> bool {
+        let Some(lockout) = Lockout::parse(message) else {
+            return false;
+        };
+        tracing::debug!("account is locked: {:?}", lockout);
+
+        let until = match lockout {
+            Lockout::For(remaining) => Instant::now().checked_add(remaining),
+            Lockout::Indefinite => None,
+        };
+        self.frontend
+            .send(AuthenticationAgentEvent::LockedOut {
+                cookie: cookie.to_string(),
+                until,
+            })
+            .await;
+        true
+    }
+
+    /// Runs the helper for whichever identity the user picks until PAM accepts one of them.
+    ///
+    /// The helper is started as soon as the dialog shows the request, before the user typed
+    /// anything, so modules like pam_fprintd and pam_u2f can ask for a finger or a touch right away.
+    async fn authenticate(&self, session: &mut Session, cookie: &str) -> Result<()> {
+        let mut user: Option<String> = None;
+        let mut failures: u32 = 0;
+
+        loop {
+            let Some(username) = user.clone() else {
+                match &session.recv().await? {
+                    AuthenticationUserEvent::Canceled { .. } => {
+                        return Err(PolkitError::Cancelled(
+                            "User cancelled the authentication.".to_string(),
+                        ));
+                    }
+                    AuthenticationUserEvent::SelectedIdentity { username, .. } => {
+                        user = Some(username.clone());
+                    }
+                    AuthenticationUserEvent::ProvidedResponse { .. }
+                    | AuthenticationUserEvent::ProvidedNewPassword { .. } => {
+                        tracing::debug!("ignoring prompt response, the helper is not running");
+                    }
+                    // The session only uses these to keep the request from timing out.
+                    AuthenticationUserEvent::Active { .. } => {}
+                }
+                continue;
+            };
+
+            let mut helper = match self.backend.connect(&username, cookie).await {
+                Ok(helper) => helper,
+                Err(e) => {
+                    tracing::error!("could not start the authentication helper: {}", e);
+                    self.frontend
+                        .send(AuthenticationAgentEvent::AuthorizationRetry {
+                            cookie: cookie.to_string(),
+                            reason: FailureReason::HelperUnavailable,
+                            messages: Vec::new(),
+                        })
+                        .await;
+                    user = None;
+                    continue;
+                }
+            };
+
+            match self
+                .converse(session, cookie, &mut helper, &username)
+                .await?
+            {
+                Attempt::Succeeded => return Ok(()),
+                Attempt::Failed { asked_user } => {
+                    failures += 1;
+                    if self.max_attempts != 0 && failures >= self.max_attempts {
+                        tracing::info!(
+                            "giving up on {} after {} failed attempts",
+                            cookie,
+                            failures
+                        );
+                        self.frontend
+                            .send(AuthenticationAgentEvent::AttemptsExhausted {
+                                cookie: cookie.to_string(),
+                            })
+                            .await;
+                        return Err(PolkitError::Cancelled(
+                            "Too many failed authentication attempts.".to_string(),
+                        ));
+                    }
+                    // Start over right away so a fingerprint can be tried again. This waits for
+                    // the user if they didn't take part, PAM would otherwise fail the same way
+                    // forever.
+                    if !asked_user {
+                        user = None;
+                    }
+                }
+                Attempt::Switched(other) => {
+                    tracing::debug!("restarting the helper for {}", other);
+                    user = Some(other);
+                }
+            }
+        }
+    }
+
+    /// Talks to one running helper until PAM reaches a verdict or the user picks another identity.
+    ///
+    /// PAM may ask any number of questions, each one is forwarded as its own numbered
+    /// [`AuthenticationAgentEvent::Prompt`]. Only the answer to the question that is currently open
+    /// is passed on, and the user can cancel or switch identities at every step.
+    async fn converse(
+        &self,
+        session: &mut Session,
+        cookie: &str,
+        helper: &mut HelperConnection,
+        username: &str,
+    ) -> Result<Attempt> {
+        // Everything PAM said during this attempt, to explain a failure with.
+        let mut messages: Vec<String> = Vec::new();
+        let mut open: Option<OpenQuestion> = None;
+        let mut step: u32 = 0;
+        let mut asked_user = false;
+        // The answer to the first password prompt, PAM asks for it again before changing an expired
+        // password. Other secrets, like one time codes, are never kept.
+        let mut password: Option<Zeroizing<String>> = None;
+        // Set once the password expired and the user picked a new one. PAM's complaints about it
+        // are shown next to the new password fields.
+        let mut new_password: Option<Zeroizing<String>> = None;
+        let mut change_errors: Vec<String> = Vec::new();
+
+        loop {
+            // Keep listening to the user while the helper is busy, a slow PAM module or one that
+            // waits for a device would otherwise leave the dialog stuck.
+            let message = tokio::select! {
+                message = helper.next_message() => message?,
+                event = session.recv() => {
+                    match (&event?, open) {
+                        (AuthenticationUserEvent::Canceled { .. }, _) => {
+                            tracing::debug!("authentication cancelled at step {}", step);
+                            helper.kill();
+                            return Err(PolkitError::Cancelled(
+                                "User cancelled the authentication.".to_string(),
+                            ));
+                        }
+                        (AuthenticationUserEvent::SelectedIdentity { username: other, .. }, _)
+                            if other != username =>
+                        {
+                            helper.kill();
+                            return Ok(Attempt::Switched(other.clone()));
+                        }
+                        (
+                            AuthenticationUserEvent::ProvidedResponse { step: answered, response, .. },
+                            Some(OpenQuestion::Prompt { step: asked, password: is_password }),
+                        ) if *answered == asked => {
+                            open = None;
+                            asked_user = true;
+                            helper.send_line(response).await?;
+                            if is_password && password.is_none() {
+                                password = Some(Zeroizing::new(response.clone()));
+                            }
+                        }
+                        (
+                            AuthenticationUserEvent::ProvidedNewPassword { password: chosen, .. },
+                            Some(OpenQuestion::NewPassword),
+                        ) => {
+                            open = None;
+                            helper.send_line(chosen).await?;
+                            new_password = Some(Zeroizing::new(chosen.clone()));
+                        }
+                        (event, _) => tracing::debug!("ignoring stale user event {:?}", event),
+                    }
+                    continue;
+                }
+            };
+            // A helper that exits without a verdict failed as far as we are concerned.
+            let message = message.unwrap_or(HelperMessage::Failure);
+
+            match message {
+                HelperMessage::PromptEchoOff(prompt) => {
+                    tracing::debug!("received secret prompt from helper: '{}'", prompt);
+
+                    let kind = SecretPrompt::classify(&prompt);
+                    match kind {
+                        SecretPrompt::Current if password.is_some() => {
+                            helper.send_line(password.as_ref().unwrap()).await?;
+                        }
+                        SecretPrompt::New if password.is_some() => {
+                            let error =
+                                (!change_errors.is_empty()).then(|| change_errors.join("\n"));
+                            change_errors.clear();
+                            open = Some(OpenQuestion::NewPassword);
+                            self.frontend
+                                .send(AuthenticationAgentEvent::PasswordChange {
+                                    cookie: cookie.to_string(),
+                                    error,
+                                })
+                                .await;
+                        }
+                        SecretPrompt::Retype if new_password.is_some() => {
+                            helper.send_line(new_password.as_ref().unwrap()).await?;
+                        }
+                        _ => {
+                            step += 1;
+                            open = Some(OpenQuestion::Prompt {
+                                step,
+                                password: kind.is_current_password(),
+                            });
+                            self.ask(cookie, step, prompt, false).await;
+                        }
+                    }
+                }
+                HelperMessage::PromptEchoOn(prompt) => {
+                    tracing::debug!("received prompt from helper: '{}'", prompt);
+
+                    step += 1;
+                    open = Some(OpenQuestion::Prompt {
+                        step,
+                        password: false,
+                    });
+                    self.ask(cookie, step, prompt, true).await;
+                }
+                HelperMessage::ErrorMsg(msg) => {
+                    tracing::debug!("helper replied with error: {}", msg);
+
+                    messages.push(msg.clone());
+                    if new_password.is_some() {
+                        change_errors.push(msg);
+                    } else if !self.report_lockout(cookie, &msg).await {
+                        self.frontend
+                            .send(AuthenticationAgentEvent::Error {
+                                cookie: cookie.to_string(),
+                                message: msg,
+                            })
+                            .await;
+                    }
+                }
+                HelperMessage::TextInfo(msg) => {
+                    tracing::debug!("helper replied with info: {}", msg);
+
+                    messages.push(msg.clone());
+                    if !self.report_lockout(cookie, &msg).await {
+                        self.frontend
+                            .send(AuthenticationAgentEvent::Info {
+                                cookie: cookie.to_string(),
+                                message: msg,
+                            })
+                            .await;
+                    }
+                }
+                HelperMessage::Failure => {
+                    tracing::debug!("helper replied with failure.");
+
+                    let reason = FailureReason::classify(&messages);
+                    tracing::debug!("authentication failed: {:?}", reason);
+                    self.frontend
+                        .send(AuthenticationAgentEvent::AuthorizationRetry {
+                            cookie: cookie.to_string(),
+                            reason,
+                            messages,
+                        })
+                        .await;
+                    return Ok(Attempt::Failed { asked_user });
+                }
+                HelperMessage::Success => {
+                    tracing::debug!("helper replied with success.");
+
+                    self.frontend
+                        .send(AuthenticationAgentEvent::AuthorizationSucceeded {
+                            cookie: cookie.to_string(),
+                        })
+                        .await;
+                    return Ok(Attempt::Succeeded);
+                }
+                HelperMessage::Unknown(line) => {
+                    tracing::debug!("ignoring unknown helper output: {}", line);
+                }
+            }
+        }
+    }
+
+    /// Forwards a question from the helper to the user.
+    async fn ask(&self, cookie: &str, step: u32, prompt: String, echo: bool) {
+        self.frontend
+            .send(AuthenticationAgentEvent::Prompt {
+                cookie: cookie.to_string(),
+                step,
+                prompt,
+                echo,
+            })
+            .await;
+    }
+}
+
+/// The question the helper is currently waiting on the user for.
+#[derive(Debug, Clone, Copy)]
+enum OpenQuestion {
+    /// An [`AuthenticationAgentEvent::Prompt`], answered by the response for the same step.
+    /// `password` is set when the answer is the account's password.
+    Prompt { step: u32, password: bool },
+    /// An [`AuthenticationAgentEvent::PasswordChange`].
+    NewPassword,
+}
+
+/// How a single conversation with the helper ended.
+#[derive(Debug)]
+enum Attempt {
+    Succeeded,
+    /// PAM refused. `asked_user` is set if the user answered anything during the attempt.
+    Failed {
+        asked_user: bool,
+    },
+    /// The user picked another identity, the helper has to be restarted for them.
+    Switched(String),
+}
+
+/// Collects the details pkexec and polkit attach to a request into something the frontend can show.
+fn request_details(
+    action_id: &str,
+    icon_name: &str,
+    details: &HashMap<String, String>,
+    action: Option<&ActionDescription>,
+) -> RequestDetails {
+    let executable = details
+        .get("polkit.subject-pid")
+        .or_else(|| details.get("polkit.caller-pid"))
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .and_then(|pid| std::fs::read_link(format!("/proc/{pid}/exe")).ok())
+        .map(|path| path.display().to_string());
+
+    RequestDetails {
+        action_id: action_id.to_string(),
+        icon_name: Some(icon_name)
+            .filter(|i| !i.is_empty())
+            .or_else(|| {
+                action
+                    .map(|a| a.icon_name.as_str())
+                    .filter(|i| !i.is_empty())
+            })
+            .map(str::to_string),
+        description: action.map(|a| a.description.clone()),
+        vendor: action
+            .map(|a| a.vendor_name.clone())
+            .filter(|v| !v.is_empty()),
+        vendor_url: action
+            .map(|a| a.vendor_url.clone())
+            .filter(|v| !v.is_empty()),
+        program: details.get("program").cloned(),
+        command_line: details.get("command_line").cloned(),
+        user: details.get("user").cloned(),
+        executable,
+        authorization: action.and_then(|a| a.implicit_active.description()),
+    }
 }
 
 #[interface(name = "org.freedesktop.PolicyKit1.AuthenticationAgent")]
 impl AuthenticationAgent {
-    async fn cancel_authentication(&self, cookie: &str) {
+    async fn cancel_authentication(
+        &self,
+        cookie: &str,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<()> {
+        self.check_caller(&header, connection, "CancelAuthentication")
+            .await?;
+
         tracing::debug!("received request to cancel authentication for {}", cookie);
-        self.sender
+        self.frontend
             .send(AuthenticationAgentEvent::Canceled {
                 cookie: cookie.to_owned(),
             })
-            .await
-            .unwrap();
+            .await;
+        self.sessions
+            .dispatch(AuthenticationUserEvent::Canceled {
+                cookie: cookie.to_owned(),
+            })
+            .await;
+        Ok(())
     }
 
+    // The arguments are fixed by the D-Bus interface, plus the header and connection from zbus.
+    #[allow(clippy::too_many_arguments)]
     async fn begin_authentication(
-        &mut self,
+        &self,
         action_id: &str,
         message: &str,
         icon_name: &str,
         details: HashMap<String, String>,
         cookie: &str,
         identities: Vec<Identity<'_>>,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
     ) -> Result<()> {
+        self.check_caller(&header, connection, "BeginAuthentication")
+            .await?;
+
         tracing::info!("received request to authenticate");
         tracing::debug!(action_id = action_id, message = message, icon_name = icon_name, details = ?details, cookie = cookie, identities = ?identities);
 
-        let mut names: Vec<String> = Vec::new();
+        // Open the session before looking anything up, so a cancel that arrives meanwhile isn't
+        // dropped for an unknown cookie.
+        let mut session = self.sessions.open(cookie)?;
+
+        let mut users: Vec<UserIdentity> = Vec::new();
         for identity in identities.iter() {
-            let details = identity.get_details();
-            if identity.get_kind() == "unix-user" {
-                let Value::U32(uid) = details["uid"] else {
+            let resolved = match (
+                identity.get_kind(),
+                identity.get_id("uid"),
+                identity.get_id("gid"),
+            ) {
+                ("unix-user", Some(uid), _) => vec![UserIdentity::from_uid(connection, uid).await],
+                ("unix-group", _, Some(gid)) => UserIdentity::from_gid(connection, gid).await,
+                _ => {
+                    tracing::warn!(
+                        "ignoring identity polkit offered that we can't use: {}",
+                        identity
+                    );
                     continue;
-                };
-                if let Ok(Some(u)) = etc_passwd::Passwd::from_uid(uid) {
-                    if let Ok(n) = u.name.into_string() {
-                        names.push(n);
-                    }
+                }
+            };
+            // A user may be offered directly and through a group, only show them once.
+            for user in resolved {
+                if user.uid.is_none() || !users.iter().any(|u| u.uid == user.uid) {
+                    users.push(user);
                 }
             }
         }
 
-        self.sender
+        if session.cancelled() {
+            tracing::debug!(
+                "request {} was cancelled while resolving identities",
+                cookie
+            );
+            return Err(PolkitError::Cancelled(
+                "The authentication was cancelled.".to_string(),
+            ));
+        }
+
+        self.frontend
             .send(AuthenticationAgentEvent::Started {
                 cookie: cookie.to_string(),
                 message: message.to_string(),
-                names,
+                identities: users,
+                details: Box::new(request_details(
+                    action_id,
+                    icon_name,
+                    &details,
+                    self.actions.get(action_id).as_ref(),
+                )),
             })
+            .await;
+
+        let result = self.authenticate(&mut session, cookie).await;
+        if result.is_err() {
+            // Let the frontend move on to the next queued request, it ignores cookies it isn't
+            // showing.
+            self.frontend
+                .send(AuthenticationAgentEvent::Canceled {
+                    cookie: cookie.to_string(),
+                })
+                .await;
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        frontend::{self, FrontendChannel},
+        helper::{MockBackend, MockStep},
+    };
+
+    const COOKIE: &str = "cookie";
+    const USER: &str = "alice";
+
+    fn agent(script: Vec<MockStep>, max_attempts: u32) -> (AuthenticationAgent, FrontendChannel) {
+        idle_agent(script, max_attempts, None)
+    }
+
+    fn idle_agent(
+        script: Vec<MockStep>,
+        max_attempts: u32,
+        idle_timeout: Option<Duration>,
+    ) -> (AuthenticationAgent, FrontendChannel) {
+        let (proxy, answers, channel) = frontend::channel();
+        let agent = AuthenticationAgent::with_backend(
+            proxy,
+            answers,
+            Box::new(MockBackend::new(script)),
+            max_attempts,
+            idle_timeout,
+            ActionCache::default(),
+        );
+        (agent, channel)
+    }
+
+    /// Runs [`AuthenticationAgent::authenticate`] while `user` plays the frontend's part.
+    async fn authenticate(
+        agent: &AuthenticationAgent,
+        user: impl Future<Output = ()>,
+    ) -> Result<()> {
+        let mut session = agent.sessions.open(COOKIE).unwrap();
+        let conversation = async { tokio::join!(agent.authenticate(&mut session, COOKIE), user) };
+        tokio::time::timeout(Duration::from_secs(5), conversation)
             .await
-            .map_err(|_| PolkitError::Failed("Failed to send data.".to_string()))?;
+            .expect("the conversation got stuck")
+            .0
+    }
 
-        loop {
-            match &self.receiver.recv().await.ok_or_else(|| {
-                PolkitError::Failed("Failed to receive data. channel closed".to_string())
-            })? {
-                AuthenticationUserEvent::Canceled { cookie: c } => {
-                    if c == cookie {
-                        return Err(PolkitError::Cancelled(
-                            "User cancelled the authentication.".to_string(),
-                        ));
-                    }
-                }
-                AuthenticationUserEvent::ProvidedPassword {
-                    cookie: c,
-                    username: user,
-                    password: pw,
-                } => {
-                    if c == cookie {
-                        let mut stream = UnixStream::connect(self.config.get_socket_path()).await;
-
-                        let (reader, mut writer): (
-                            BufReader<Box<dyn tokio::io::AsyncRead + Unpin + Send>>,
-                            Box<dyn tokio::io::AsyncWrite + Unpin + Send>,
-                        ) = if let Ok(stream) = &mut stream {
-                            let (read_half, mut write_half) = stream.split();
-
-                            write_half.write_all(user.as_bytes()).await?;
-                            write_half.write_all(b"\n").await?;
-                            write_half.write_all(cookie.as_bytes()).await?;
-                            write_half.write_all(b"\n").await?;
-
-                            (BufReader::new(Box::new(read_half)), Box::new(write_half))
-                        } else {
-                            let mut child = process::Command::new(self.config.get_helper_path())
-                                .arg(user)
-                                .env("LC_ALL", "C")
-                                .stdin(Stdio::piped())
-                                .stdout(Stdio::piped())
-                                .spawn()
-                                .map_err(|_| {
-                                    PolkitError::Failed(
-                                        "Failed to the spawn polkit authentication helper."
-                                            .to_string(),
-                                    )
-                                })?;
-
-                            let mut stdin = child.stdin.take().ok_or(PolkitError::Failed(
-                                "Child did not have stdin.".to_string(),
-                            ))?;
-                            let stdout = child.stdout.take().ok_or(PolkitError::Failed(
-                                "Child did not have stdout.".to_string(),
-                            ))?;
-
-                            stdin.write_all(cookie.as_bytes()).await?;
-                            stdin.write_all(b"\n").await?;
-
-                            (BufReader::new(Box::new(stdout)), Box::new(stdin))
-                        };
-
-                        let mut last_info: Option<String> = None;
-
-                        let mut lines = reader.lines();
-                        while let Some(line) = lines.next_line().await? {
-                            tracing::debug!("helper stdout: {}", line);
-                            if let Some(sliced) = line.strip_prefix("PAM_PROMPT_ECHO_OFF") {
-                                tracing::debug!("received request from helper: '{}'", sliced);
-                                if sliced.trim() == "Password:" {
-                                    tracing::debug!("helper replied with request for password");
-                                    writer.write_all(pw.as_bytes()).await?;
-                                    writer.write_all(b"\n").await?;
-                                }
-                            } else if let Some(info) = line.strip_prefix("PAM_TEXT_INFO") {
-                                let msg = info.trim().to_string();
-                                tracing::debug!("helper replied with info: {}", msg);
-
-                                if msg.contains("minute") && msg.contains("unlock") {
-                                    last_info = Some(msg.clone());
-                                    self.sender
-                                        .send(AuthenticationAgentEvent::AuthorizationRetry {
-                                            cookie: cookie.to_string(),
-                                            retry_message: Some(msg),
-                                        })
-                                        .await
-                                        .unwrap();
-                                }
-                            } else if line.starts_with("FAILURE") {
-                                tracing::debug!("helper replied with failure.");
-
-                                let retry_msg = last_info.clone().unwrap_or_else(|| {
-                                    gettext("Authentication failed. Please try again.")
-                                });
-                                self.sender
-                                    .send(AuthenticationAgentEvent::AuthorizationRetry {
-                                        cookie: cookie.to_string(),
-                                        retry_message: Some(retry_msg),
-                                    })
-                                    .await
-                                    .unwrap();
-                                continue;
-                            } else if line.starts_with("SUCCESS") {
-                                tracing::debug!("helper replied with success.");
-
-                                self.sender
-                                    .send(AuthenticationAgentEvent::AuthorizationSucceeded {
-                                        cookie: cookie.to_string(),
-                                    })
-                                    .await
-                                    .unwrap();
-                                return Ok(());
-                            }
-                        }
-                        writer.flush().await?;
+    fn password_prompt() -> Vec<MockStep> {
+        vec![
+            MockStep::Send(HelperMessage::PromptEchoOff("Password:".to_string())),
+            MockStep::Expect("hunter2".to_string()),
+            MockStep::Send(HelperMessage::Success),
+        ]
+    }
+
+    async fn send(channel: &FrontendChannel, event: AuthenticationUserEvent) {
+        channel.answers.send(event).await.unwrap();
+    }
+
+    async fn select(channel: &FrontendChannel) {
+        send(
+            channel,
+            AuthenticationUserEvent::SelectedIdentity {
+                cookie: COOKIE.to_string(),
+                username: USER.to_string(),
+            },
+        )
+        .await;
+    }
+
+    async fn respond(channel: &FrontendChannel, step: u32, response: &str) {
+        send(
+            channel,
+            AuthenticationUserEvent::ProvidedResponse {
+                cookie: COOKIE.to_string(),
+                step,
+                response: response.to_string(),
+            },
+        )
+        .await;
+    }
+
+    async fn next(channel: &mut FrontendChannel) -> AuthenticationAgentEvent {
+        channel.events.recv().await.expect("the agent hung up")
+    }
+
+    /// Waits for the prompt with the given step and checks what it asks.
+    async fn expect_prompt(channel: &mut FrontendChannel, step: u32, text: &str, echo: bool) {
+        let event = next(channel).await;
+        assert!(
+            matches!(
+                &event,
+                AuthenticationAgentEvent::Prompt { step: s, prompt, echo: e, .. }
+                    if *s == step && prompt == text && *e == echo
+            ),
+            "expected prompt {step} {text:?}, got {event:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn succeeds_with_the_right_password() {
+        let (agent, mut channel) = agent(password_prompt(), 3);
+        let result = authenticate(&agent, async {
+            select(&channel).await;
+            expect_prompt(&mut channel, 1, "Password:", false).await;
+            respond(&channel, 1, "hunter2").await;
+            let event = next(&mut channel).await;
+            assert!(
+                matches!(
+                    event,
+                    AuthenticationAgentEvent::AuthorizationSucceeded { .. }
+                ),
+                "{event:?}"
+            );
+        })
+        .await;
+        assert!(result.is_ok(), "{result:?}");
+    }
+
+    #[tokio::test]
+    async fn retries_after_a_wrong_password() {
+        let (agent, mut channel) = agent(password_prompt(), 3);
+        let result = authenticate(&agent, async {
+            select(&channel).await;
+            expect_prompt(&mut channel, 1, "Password:", false).await;
+            respond(&channel, 1, "wrong").await;
+            let event = next(&mut channel).await;
+            assert!(
+                matches!(
+                    event,
+                    AuthenticationAgentEvent::AuthorizationRetry {
+                        reason: FailureReason::WrongPassword,
+                        ..
                     }
-                }
+                ),
+                "{event:?}"
+            );
+            // The user answered, so the helper starts over without waiting for them.
+            expect_prompt(&mut channel, 1, "Password:", false).await;
+            respond(&channel, 1, "hunter2").await;
+            let event = next(&mut channel).await;
+            assert!(
+                matches!(
+                    event,
+                    AuthenticationAgentEvent::AuthorizationSucceeded { .. }
+                ),
+                "{event:?}"
+            );
+        })
+        .await;
+        assert!(result.is_ok(), "{result:?}");
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        let (agent, mut channel) = agent(password_prompt(), 2);
+        let result = authenticate(&agent, async {
+            select(&channel).await;
+            for _ in 0..2 {
+                expect_prompt(&mut channel, 1, "Password:", false).await;
+                respond(&channel, 1, "wrong").await;
+                let event = next(&mut channel).await;
+                assert!(
+                    matches!(event, AuthenticationAgentEvent::AuthorizationRetry { .. }),
+                    "{event:?}"
+                );
+            }
+            let event = next(&mut channel).await;
+            assert!(
+                matches!(event, AuthenticationAgentEvent::AttemptsExhausted { .. }),
+                "{event:?}"
+            );
+        })
+        .await;
+        assert!(
+            matches!(result, Err(PolkitError::Cancelled(_))),
+            "{result:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn forwards_every_prompt_in_order() {
+        let script = vec![
+            MockStep::Send(HelperMessage::PromptEchoOn("Token:".to_string())),
+            MockStep::Expect("123456".to_string()),
+            MockStep::Send(HelperMessage::TextInfo("Token accepted".to_string())),
+            MockStep::Send(HelperMessage::PromptEchoOff("Password:".to_string())),
+            MockStep::Expect("hunter2".to_string()),
+            MockStep::Send(HelperMessage::Success),
+        ];
+        let (agent, mut channel) = agent(script, 3);
+        let result = authenticate(&agent, async {
+            select(&channel).await;
+            expect_prompt(&mut channel, 1, "Token:", true).await;
+            respond(&channel, 1, "123456").await;
+            let event = next(&mut channel).await;
+            assert!(
+                matches!(&event, AuthenticationAgentEvent::Info { message, .. } if message == "Token accepted"),
+                "{event:?}"
+            );
+            expect_prompt(&mut channel, 2, "Password:", false).await;
+            // An answer to a question that is no longer open never reaches the helper.
+            respond(&channel, 1, "stale").await;
+            respond(&channel, 2, "hunter2").await;
+            let event = next(&mut channel).await;
+            assert!(
+                matches!(event, AuthenticationAgentEvent::AuthorizationSucceeded { .. }),
+                "{event:?}"
+            );
+        })
+        .await;
+        assert!(result.is_ok(), "{result:?}");
+    }
+
+    #[tokio::test]
+    async fn only_repeats_the_password() {
+        let script = vec![
+            MockStep::Send(HelperMessage::PromptEchoOff(
+                "Verification code:".to_string(),
+            )),
+            MockStep::Expect("123456".to_string()),
+            MockStep::Send(HelperMessage::PromptEchoOff("Password:".to_string())),
+            MockStep::Expect("hunter2".to_string()),
+            // PAM asks for the password again before changing it, which the agent answers itself.
+            MockStep::Send(HelperMessage::PromptEchoOff(
+                "Current password:".to_string(),
+            )),
+            MockStep::Expect("hunter2".to_string()),
+            MockStep::Send(HelperMessage::Success),
+        ];
+        let (agent, mut channel) = agent(script, 3);
+        let result = authenticate(&agent, async {
+            select(&channel).await;
+            expect_prompt(&mut channel, 1, "Verification code:", false).await;
+            respond(&channel, 1, "123456").await;
+            expect_prompt(&mut channel, 2, "Password:", false).await;
+            respond(&channel, 2, "hunter2").await;
+            let event = next(&mut channel).await;
+            assert!(
+                matches!(
+                    event,
+                    AuthenticationAgentEvent::AuthorizationSucceeded { .. }
+                ),
+                "{event:?}"
+            );
+        })
+        .await;
+        assert!(result.is_ok(), "{result:?}");
+    }
+
+    /// Waits until the frontend was both asked for the password and warned about the timeout,
+    /// which race each other with a timeout this short.
+    async fn expect_prompt_and_warning(channel: &mut FrontendChannel) {
+        let (mut prompted, mut warned) = (false, false);
+        while !(prompted && warned) {
+            match next(channel).await {
+                AuthenticationAgentEvent::Prompt { step: 1, .. } => prompted = true,
+                AuthenticationAgentEvent::IdleWarning { until: Some(_), .. } => warned = true,
+                event => panic!("expected the prompt and a warning, got {event:?}"),
             }
         }
     }
+
+    #[tokio::test]
+    async fn cancels_when_left_alone() {
+        let (agent, mut channel) =
+            idle_agent(password_prompt(), 3, Some(Duration::from_millis(100)));
+        let result = authenticate(&agent, async {
+            select(&channel).await;
+            expect_prompt_and_warning(&mut channel).await;
+        })
+        .await;
+        assert!(
+            matches!(result, Err(PolkitError::Cancelled(_))),
+            "{result:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn activity_withdraws_the_idle_warning() {
+        let (agent, mut channel) =
+            idle_agent(password_prompt(), 3, Some(Duration::from_millis(500)));
+        let result = authenticate(&agent, async {
+            select(&channel).await;
+            expect_prompt_and_warning(&mut channel).await;
+            send(
+                &channel,
+                AuthenticationUserEvent::Active {
+                    cookie: COOKIE.to_string(),
+                },
+            )
+            .await;
+            let event = next(&mut channel).await;
+            assert!(
+                matches!(
+                    event,
+                    AuthenticationAgentEvent::IdleWarning { until: None, .. }
+                ),
+                "{event:?}"
+            );
+            respond(&channel, 1, "hunter2").await;
+            loop {
+                match next(&mut channel).await {
+                    AuthenticationAgentEvent::IdleWarning { .. } => {}
+                    AuthenticationAgentEvent::AuthorizationSucceeded { .. } => break,
+                    event => panic!("expected success, got {event:?}"),
+                }
+            }
+        })
+        .await;
+        assert!(result.is_ok(), "{result:?}");
+    }
+
+    #[tokio::test]
+    async fn stops_when_cancelled() {
+        let (agent, mut channel) = agent(password_prompt(), 3);
+        let result = authenticate(&agent, async {
+            select(&channel).await;
+            expect_prompt(&mut channel, 1, "Password:", false).await;
+            send(
+                &channel,
+                AuthenticationUserEvent::Canceled {
+                    cookie: COOKIE.to_string(),
+                },
+            )
+            .await;
+        })
+        .await;
+        assert!(
+            matches!(result, Err(PolkitError::Cancelled(_))),
+            "{result:?}"
+        );
+    }
 }
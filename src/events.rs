@@ -1,15 +1,86 @@
-use std::fmt::Debug;
+use std::{
+    fmt::{Debug, Display},
+    time::Instant,
+};
+
+use gettextrs::gettext;
+
+use crate::{failure::FailureReason, identity::UserIdentity};
+
+/// What is being authorized, as far as polkit told us.
+#[derive(Debug, Clone, Default, zeroize::Zeroize)]
+pub struct RequestDetails {
+    pub action_id: String,
+    /// The themed icon polkit asked us to show, if the action sets one.
+    pub icon_name: Option<String>,
+    /// The action's description from its policy file.
+    pub description: Option<String>,
+    pub vendor: Option<String>,
+    pub vendor_url: Option<String>,
+    /// The program pkexec is asked to run.
+    pub program: Option<String>,
+    pub command_line: Option<String>,
+    /// The user the program will run as.
+    pub user: Option<String>,
+    /// The resolved executable of the process that asked for authorization.
+    pub executable: Option<String>,
+    /// What polkit asks for by default, like administrator authentication that is remembered.
+    pub authorization: Option<String>,
+}
+
+/// One "label: value" line for every detail polkit gave us.
+impl Display for RequestDetails {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let vendor = match (&self.vendor, &self.vendor_url) {
+            (Some(vendor), Some(url)) => Some(format!("{vendor} ({url})")),
+            (vendor, url) => vendor.clone().or(url.clone()),
+        };
+        let lines = [
+            (gettext("Action"), Some(&self.action_id)),
+            (gettext("Description"), self.description.as_ref()),
+            (gettext("Vendor"), vendor.as_ref()),
+            (gettext("Program"), self.program.as_ref()),
+            (gettext("Command line"), self.command_line.as_ref()),
+            (gettext("Run as"), self.user.as_ref()),
+            (gettext("Requested by"), self.executable.as_ref()),
+            (gettext("Authorization"), self.authorization.as_ref()),
+        ]
+        .into_iter()
+        .filter_map(|(label, value)| value.map(|v| format!("{label}: {v}")))
+        .collect::<Vec<_>>();
+        f.write_str(&lines.join("\n"))
+    }
+}
 
 #[derive(Clone, zeroize::ZeroizeOnDrop)]
 pub enum AuthenticationUserEvent {
     /// The user canceled the authentication.
     Canceled { cookie: String },
-    /// The user provided their password.
-    ProvidedPassword {
+    /// The dialog shows this request with the given identity selected, so the helper can start.
+    SelectedIdentity { cookie: String, username: String },
+    /// The user answered the prompt with the same `step`.
+    ProvidedResponse {
         cookie: String,
-        username: String,
-        password: String,
+        step: u32,
+        response: String,
     },
+    /// The user chose a new password after theirs expired. Both entries already matched.
+    ProvidedNewPassword { cookie: String, password: String },
+    /// The request is shown and the user is still there, like typing into it. The idle timeout
+    /// starts with the first event the frontend sends about a request and restarts with every one.
+    Active { cookie: String },
+}
+
+impl AuthenticationUserEvent {
+    pub fn cookie(&self) -> &str {
+        match self {
+            Self::Canceled { cookie }
+            | Self::SelectedIdentity { cookie, .. }
+            | Self::ProvidedResponse { cookie, .. }
+            | Self::ProvidedNewPassword { cookie, .. }
+            | Self::Active { cookie } => cookie,
+        }
+    }
 }
 
 #[derive(Clone, zeroize::ZeroizeOnDrop)]
@@ -18,7 +89,9 @@ pub enum AuthenticationAgentEvent {
     Started {
         cookie: String,
         message: String,
-        names: Vec<String>,
+        identities: Vec<UserIdentity>,
+        // Boxed, it would make every other event as large as this one.
+        details: Box<RequestDetails>,
     },
     /// Polkit sent a request for the authentication to be canceled.
     Canceled { cookie: String },
@@ -26,10 +99,44 @@ pub enum AuthenticationAgentEvent {
     AuthorizationSucceeded { cookie: String },
     // There is already an authentication event being handled.
     //AlreadyRunning { cookie: String },
-    /// The user provided a password, but it was incorrect.
+    /// The request failed too often and is cancelled, the dialog should say so before closing.
+    AttemptsExhausted { cookie: String },
+    /// The attempt failed. `messages` is everything PAM said during it.
     AuthorizationRetry {
         cookie: String,
-        retry_message: Option<String>,
+        #[zeroize(skip)]
+        reason: FailureReason,
+        messages: Vec<String>,
+    },
+    /// The helper asked a question that the user has to answer, like the password or a one time
+    /// code. `step` counts the questions of one conversation, the answer has to repeat it.
+    Prompt {
+        cookie: String,
+        step: u32,
+        prompt: String,
+        echo: bool,
+    },
+    /// The password has expired and PAM wants a new one. `error` is why the last one was rejected.
+    PasswordChange {
+        cookie: String,
+        error: Option<String>,
+    },
+    /// The account is locked, until the given time if the PAM module said how long for.
+    LockedOut {
+        cookie: String,
+        #[zeroize(skip)]
+        until: Option<Instant>,
+    },
+    /// The helper sent an informational message.
+    Info { cookie: String, message: String },
+    /// The helper sent an error message.
+    Error { cookie: String, message: String },
+    /// Nobody did anything for a while, the request is cancelled at `until` unless the user does.
+    /// `None` once they did.
+    IdleWarning {
+        cookie: String,
+        #[zeroize(skip)]
+        until: Option<Instant>,
     },
 }
 
@@ -42,13 +149,21 @@ impl Debug for AuthenticationUserEvent {
             Self::Canceled { cookie } => {
                 f.debug_struct("Canceled").field("cookie", &cookie).finish()
             }
-            Self::ProvidedPassword {
-                cookie, username, ..
-            } => f
-                .debug_struct("ProvidedPassword")
+            Self::SelectedIdentity { cookie, username } => f
+                .debug_struct("SelectedIdentity")
                 .field("cookie", &cookie)
                 .field("username", &username)
                 .finish(),
+            Self::ProvidedResponse { cookie, step, .. } => f
+                .debug_struct("ProvidedResponse")
+                .field("cookie", &cookie)
+                .field("step", &step)
+                .finish(),
+            Self::ProvidedNewPassword { cookie, .. } => f
+                .debug_struct("ProvidedNewPassword")
+                .field("cookie", &cookie)
+                .finish(),
+            Self::Active { cookie } => f.debug_struct("Active").field("cookie", &cookie).finish(),
         }
     }
 }
@@ -59,12 +174,14 @@ impl Debug for AuthenticationAgentEvent {
             Self::Started {
                 cookie,
                 message,
-                names,
+                identities,
+                details,
             } => f
                 .debug_struct("Started")
                 .field("cookie", &cookie)
                 .field("message", &message)
-                .field("names", &names)
+                .field("identities", &identities)
+                .field("details", &details)
                 .finish(),
             Self::Canceled { cookie } => {
                 f.debug_struct("Canceled").field("cookie", &cookie).finish()
@@ -73,13 +190,56 @@ impl Debug for AuthenticationAgentEvent {
                 .debug_struct("AuthorizationSucceeded")
                 .field("cookie", &cookie)
                 .finish(),
+            Self::AttemptsExhausted { cookie } => f
+                .debug_struct("AttemptsExhausted")
+                .field("cookie", &cookie)
+                .finish(),
             Self::AuthorizationRetry {
                 cookie,
-                retry_message,
+                reason,
+                messages,
             } => f
                 .debug_struct("AuthorizationRetry")
                 .field("cookie", &cookie)
-                .field("retry_message", &retry_message)
+                .field("reason", &reason)
+                .field("messages", &messages)
+                .finish(),
+            Self::Prompt {
+                cookie,
+                step,
+                prompt,
+                echo,
+            } => f
+                .debug_struct("Prompt")
+                .field("cookie", &cookie)
+                .field("step", &step)
+                .field("prompt", &prompt)
+                .field("echo", &echo)
+                .finish(),
+            Self::PasswordChange { cookie, error } => f
+                .debug_struct("PasswordChange")
+                .field("cookie", &cookie)
+                .field("error", &error)
+                .finish(),
+            Self::LockedOut { cookie, until } => f
+                .debug_struct("LockedOut")
+                .field("cookie", &cookie)
+                .field("until", &until)
+                .finish(),
+            Self::Info { cookie, message } => f
+                .debug_struct("Info")
+                .field("cookie", &cookie)
+                .field("message", &message)
+                .finish(),
+            Self::Error { cookie, message } => f
+                .debug_struct("Error")
+                .field("cookie", &cookie)
+                .field("message", &message)
+                .finish(),
+            Self::IdleWarning { cookie, until } => f
+                .debug_struct("IdleWarning")
+                .field("cookie", &cookie)
+                .field("until", &until)
                 .finish(),
         }
     }
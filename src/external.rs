@@ -0,0 +1,400 @@
+//! Publishes requests on the session bus, for shells like eww, AGS or Quickshell that draw the
+//! prompt themselves.
+//!
+//! Soteria stays the registered agent and keeps driving the helper, the shell only shows what the
+//! signals announce and answers through the methods. A request goes like this:
+//!
+//! 1. `RequestStarted` announces it, the shell answers with `SelectIdentity` to start PAM.
+//! 2. Every `Prompt` is answered by `Respond` with the same step, `PasswordChange` by
+//!    `ChangePassword`. `Info` and `Error` are only shown.
+//! 3. `Retry` means the attempt failed. PAM starts over by itself if the user answered anything,
+//!    otherwise the shell calls `SelectIdentity` again once the user wants to retry.
+//! 4. `RequestFinished` closes the request, `Cancel` can do so at any point.
+//!
+//! A request nobody answers is cancelled after the idle timeout, `IdleWarning` announces this a
+//! little before. `Activity` keeps it open while the user types.
+//!
+//! `Requests` lists what is pending, for shells that start or reload while a request is open.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use eyre::{Result, WrapErr};
+use serde::Serialize;
+use tokio::sync::mpsc;
+use zbus::{conn, fdo, interface, object_server::SignalEmitter, zvariant::Type};
+
+use crate::{
+    constants,
+    events::{AuthenticationAgentEvent, AuthenticationUserEvent, RequestDetails},
+    frontend::{Frontend, FrontendChannel},
+    future::BoxFuture,
+    identity::UserIdentity,
+};
+
+#[derive(Debug)]
+pub struct ExternalFrontend;
+
+impl ExternalFrontend {
+    /// Whether a shell asked to show the requests itself, with `--external`.
+    pub fn requested() -> bool {
+        std::env::args().any(|arg| arg == "--external")
+    }
+}
+
+impl Frontend for ExternalFrontend {
+    fn run(self: Box<Self>, channel: FrontendChannel) -> BoxFuture<'static, Result<()>> {
+        Box::pin(async { tokio::spawn(run(channel)).await? })
+    }
+}
+
+async fn run(channel: FrontendChannel) -> Result<()> {
+    let FrontendChannel {
+        mut events,
+        answers,
+    } = channel;
+    let requests = Requests::default();
+    let shown = answers.clone();
+    let connection = conn::Builder::session()?
+        .name(constants::EXTERNAL_BUS_NAME)?
+        .serve_at(
+            constants::EXTERNAL_OBJECT_PATH,
+            ExternalPrompt {
+                answers,
+                requests: requests.clone(),
+            },
+        )?
+        .build()
+        .await
+        .context("Could not claim Soteria's name on the session bus")?;
+    let emitter = SignalEmitter::new(&connection, constants::EXTERNAL_OBJECT_PATH)?;
+
+    tracing::info!(
+        "publishing authentication requests as {} on the session bus",
+        constants::EXTERNAL_BUS_NAME
+    );
+
+    while let Some(event) = events.recv().await {
+        tracing::debug!("received event {:#?}", event);
+        // Every request is published as soon as it starts, so its idle timeout starts as well.
+        if let AuthenticationAgentEvent::Started { cookie, .. } = &event {
+            let active = AuthenticationUserEvent::Active {
+                cookie: cookie.clone(),
+            };
+            if shown.send(active).await.is_err() {
+                tracing::warn!("the agent stopped listening");
+            }
+        }
+        if let Err(e) = announce(&emitter, &requests, event).await {
+            tracing::warn!("could not announce an event on the session bus: {}", e);
+        }
+    }
+    Ok(())
+}
+
+/// Every request that was started and has not finished yet, keyed by cookie.
+type Requests = Arc<Mutex<HashMap<String, PendingRequest>>>;
+
+/// A request as the shell sees it, with the question it currently has to answer.
+#[derive(Debug, Clone, Serialize, Type)]
+struct PendingRequest {
+    cookie: String,
+    message: String,
+    identities: Vec<ExternalIdentity>,
+    /// The details polkit gave us, keyed like the fields of [`RequestDetails`].
+    details: HashMap<String, String>,
+    /// The open prompt, `0` if there is none.
+    step: u32,
+    prompt: String,
+    echo: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Type)]
+struct ExternalIdentity {
+    /// What to pass to `SelectIdentity`. Empty for users and groups that could not be resolved,
+    /// which should be shown but can't be picked.
+    username: String,
+    label: String,
+    /// Path to the avatar image, empty if the user has none.
+    avatar: String,
+}
+
+impl From<&UserIdentity> for ExternalIdentity {
+    fn from(identity: &UserIdentity) -> Self {
+        Self {
+            username: identity.username.clone().unwrap_or_default(),
+            label: identity.label(),
+            avatar: identity.avatar.clone().unwrap_or_default(),
+        }
+    }
+}
+
+/// The details that are set, under the names of their fields.
+fn details_map(details: &RequestDetails) -> HashMap<String, String> {
+    [
+        ("action_id", Some(&details.action_id)),
+        ("icon_name", details.icon_name.as_ref()),
+        ("description", details.description.as_ref()),
+        ("vendor", details.vendor.as_ref()),
+        ("vendor_url", details.vendor_url.as_ref()),
+        ("program", details.program.as_ref()),
+        ("command_line", details.command_line.as_ref()),
+        ("user", details.user.as_ref()),
+        ("executable", details.executable.as_ref()),
+        ("authorization", details.authorization.as_ref()),
+    ]
+    .into_iter()
+    .filter_map(|(key, value)| value.map(|v| (key.to_string(), v.clone())))
+    .collect()
+}
+
+/// Records `event` and emits the matching signal.
+async fn announce(
+    emitter: &SignalEmitter<'_>,
+    requests: &Requests,
+    event: AuthenticationAgentEvent,
+) -> zbus::Result<()> {
+    // The prompt only stays open until the helper moves on.
+    let close_prompt = |cookie: &str| {
+        if let Some(request) = requests.lock().unwrap().get_mut(cookie) {
+            request.step = 0;
+            request.prompt.clear();
+            request.echo = false;
+        }
+    };
+    let finish = |cookie: &str| requests.lock().unwrap().remove(cookie).is_some();
+
+    match &event {
+        AuthenticationAgentEvent::Started {
+            cookie,
+            message,
+            identities,
+            details,
+        } => {
+            let request = PendingRequest {
+                cookie: cookie.clone(),
+                message: message.clone(),
+                identities: identities.iter().map(ExternalIdentity::from).collect(),
+                details: details_map(details),
+                step: 0,
+                prompt: String::new(),
+                echo: false,
+            };
+            requests
+                .lock()
+                .unwrap()
+                .insert(cookie.clone(), request.clone());
+            ExternalPrompt::request_started(
+                emitter,
+                cookie,
+                message,
+                &request.identities,
+                &request.details,
+            )
+            .await
+        }
+        AuthenticationAgentEvent::Prompt {
+            cookie,
+            step,
+            prompt,
+            echo,
+        } => {
+            if let Some(request) = requests.lock().unwrap().get_mut(cookie) {
+                request.step = *step;
+                request.prompt = prompt.clone();
+                request.echo = *echo;
+            }
+            ExternalPrompt::prompt(emitter, cookie, *step, prompt, *echo).await
+        }
+        AuthenticationAgentEvent::PasswordChange { cookie, error } => {
+            close_prompt(cookie);
+            ExternalPrompt::password_change(emitter, cookie, error.as_deref().unwrap_or_default())
+                .await
+        }
+        AuthenticationAgentEvent::AuthorizationRetry {
+            cookie,
+            reason,
+            messages,
+        } => {
+            close_prompt(cookie);
+            ExternalPrompt::retry(emitter, cookie, &reason.message(), messages).await
+        }
+        AuthenticationAgentEvent::LockedOut { cookie, until } => {
+            let seconds = until
+                .map(|until| until.saturating_duration_since(Instant::now()).as_secs())
+                .unwrap_or_default();
+            ExternalPrompt::locked_out(emitter, cookie, seconds).await
+        }
+        AuthenticationAgentEvent::IdleWarning { cookie, until } => {
+            let seconds = until
+                .map(|until| until.saturating_duration_since(Instant::now()).as_secs())
+                .unwrap_or_default();
+            ExternalPrompt::idle_warning(emitter, cookie, seconds).await
+        }
+        AuthenticationAgentEvent::Info { cookie, message } => {
+            ExternalPrompt::info(emitter, cookie, message).await
+        }
+        AuthenticationAgentEvent::Error { cookie, message } => {
+            ExternalPrompt::error(emitter, cookie, message).await
+        }
+        AuthenticationAgentEvent::AuthorizationSucceeded { cookie } if finish(cookie) => {
+            ExternalPrompt::request_finished(emitter, cookie, "succeeded").await
+        }
+        AuthenticationAgentEvent::AttemptsExhausted { cookie } if finish(cookie) => {
+            ExternalPrompt::request_finished(emitter, cookie, "exhausted").await
+        }
+        // The agent follows up every request that did not succeed with this, only the first
+        // outcome is announced.
+        AuthenticationAgentEvent::Canceled { cookie } if finish(cookie) => {
+            ExternalPrompt::request_finished(emitter, cookie, "canceled").await
+        }
+        AuthenticationAgentEvent::AuthorizationSucceeded { .. }
+        | AuthenticationAgentEvent::AttemptsExhausted { .. }
+        | AuthenticationAgentEvent::Canceled { .. } => Ok(()),
+    }
+}
+
+/// The interface shells talk to, answers are forwarded to the agent as they are.
+struct ExternalPrompt {
+    answers: mpsc::Sender<AuthenticationUserEvent>,
+    requests: Requests,
+}
+
+impl ExternalPrompt {
+    async fn forward(&self, event: AuthenticationUserEvent) -> fdo::Result<()> {
+        if !self.requests.lock().unwrap().contains_key(event.cookie()) {
+            return Err(fdo::Error::InvalidArgs(format!(
+                "There is no pending request {}.",
+                event.cookie()
+            )));
+        }
+        self.answers
+            .send(event)
+            .await
+            .map_err(|_| fdo::Error::Failed("The agent stopped.".to_string()))
+    }
+}
+
+#[interface(name = "gay.vaskel.Soteria.Prompt")]
+impl ExternalPrompt {
+    /// Every pending request, in no particular order.
+    async fn requests(&self) -> Vec<PendingRequest> {
+        self.requests.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Starts PAM for `username`, or restarts it for another identity.
+    async fn select_identity(&self, cookie: String, username: String) -> fdo::Result<()> {
+        let offered =
+            self.requests
+                .lock()
+                .unwrap()
+                .get(&cookie)
+                .is_some_and(|request| {
+                    request.identities.iter().any(|identity| {
+                        !identity.username.is_empty() && identity.username == username
+                    })
+                });
+        if !offered {
+            return Err(fdo::Error::InvalidArgs(format!(
+                "{username:?} can't authorize request {cookie}."
+            )));
+        }
+        self.forward(AuthenticationUserEvent::SelectedIdentity { cookie, username })
+            .await
+    }
+
+    /// Answers the prompt announced with the same `step`.
+    async fn respond(&self, cookie: String, step: u32, response: String) -> fdo::Result<()> {
+        self.forward(AuthenticationUserEvent::ProvidedResponse {
+            cookie,
+            step,
+            response,
+        })
+        .await
+    }
+
+    /// Sets a new password after the old one expired. The shell asks for it twice itself.
+    async fn change_password(&self, cookie: String, password: String) -> fdo::Result<()> {
+        self.forward(AuthenticationUserEvent::ProvidedNewPassword { cookie, password })
+            .await
+    }
+
+    async fn cancel(&self, cookie: String) -> fdo::Result<()> {
+        self.forward(AuthenticationUserEvent::Canceled { cookie })
+            .await
+    }
+
+    /// The user is still there, like typing an answer. Restarts the idle timeout.
+    async fn activity(&self, cookie: String) -> fdo::Result<()> {
+        self.forward(AuthenticationUserEvent::Active { cookie })
+            .await
+    }
+
+    #[zbus(signal)]
+    async fn request_started(
+        emitter: &SignalEmitter<'_>,
+        cookie: &str,
+        message: &str,
+        identities: &[ExternalIdentity],
+        details: &HashMap<String, String>,
+    ) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn prompt(
+        emitter: &SignalEmitter<'_>,
+        cookie: &str,
+        step: u32,
+        prompt: &str,
+        echo: bool,
+    ) -> zbus::Result<()>;
+
+    /// `error` is why PAM rejected the last new password, empty the first time.
+    #[zbus(signal)]
+    async fn password_change(
+        emitter: &SignalEmitter<'_>,
+        cookie: &str,
+        error: &str,
+    ) -> zbus::Result<()>;
+
+    /// `reason` is a translated explanation, `messages` everything PAM said during the attempt.
+    #[zbus(signal)]
+    async fn retry(
+        emitter: &SignalEmitter<'_>,
+        cookie: &str,
+        reason: &str,
+        messages: &[String],
+    ) -> zbus::Result<()>;
+
+    /// `seconds` is how long the account stays locked, `0` if PAM did not say.
+    #[zbus(signal)]
+    async fn locked_out(
+        emitter: &SignalEmitter<'_>,
+        cookie: &str,
+        seconds: u64,
+    ) -> zbus::Result<()>;
+
+    /// The request is cancelled in `seconds` unless the user does something, `0` once they did.
+    #[zbus(signal)]
+    async fn idle_warning(
+        emitter: &SignalEmitter<'_>,
+        cookie: &str,
+        seconds: u64,
+    ) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn info(emitter: &SignalEmitter<'_>, cookie: &str, message: &str) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn error(emitter: &SignalEmitter<'_>, cookie: &str, message: &str) -> zbus::Result<()>;
+
+    /// `outcome` is one of `succeeded`, `exhausted` or `canceled`.
+    #[zbus(signal)]
+    async fn request_finished(
+        emitter: &SignalEmitter<'_>,
+        cookie: &str,
+        outcome: &str,
+    ) -> zbus::Result<()>;
+}
@@ -0,0 +1,163 @@
+//! Works out why an authentication attempt failed from what PAM said during it.
+//!
+//! Only the untranslated messages are understood, see [`crate::helper::HELPER_LOCALE`]. Anything
+//! we don't recognise is treated as a wrong password.
+
+use gettextrs::gettext;
+
+use crate::lockout::Lockout;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureReason {
+    WrongPassword,
+    /// pam_unix: `Your account has expired; please contact your system administrator.`
+    AccountExpired,
+    /// pam_unix: `You are required to change your password immediately (password expired)`
+    PasswordExpired,
+    /// pam_access, pam_succeed_if and friends refusing the identity outright.
+    NotPermitted,
+    AccountLocked,
+    /// The helper could not be started at all.
+    HelperUnavailable,
+}
+
+impl FailureReason {
+    /// Picks the most specific reason any of the attempt's messages point at.
+    pub fn classify(messages: &[String]) -> Self {
+        let messages: Vec<String> = messages.iter().map(|m| m.to_ascii_lowercase()).collect();
+        let any = |needles: &[&str]| {
+            messages
+                .iter()
+                .any(|message| needles.iter().any(|needle| message.contains(needle)))
+        };
+
+        if messages
+            .iter()
+            .any(|message| Lockout::parse(message).is_some())
+        {
+            Self::AccountLocked
+        } else if any(&["account has expired", "account expired"]) {
+            Self::AccountExpired
+        } else if any(&[
+            "password has expired",
+            "password expired",
+            "change your password immediately",
+        ]) {
+            Self::PasswordExpired
+        } else if any(&["access denied", "permission denied", "not allowed"]) {
+            Self::NotPermitted
+        } else {
+            Self::WrongPassword
+        }
+    }
+
+    /// What the dialog tells the user, in their language.
+    pub fn message(&self) -> String {
+        match self {
+            Self::WrongPassword => gettext("Authentication failed. Please try again."),
+            Self::AccountExpired => {
+                gettext("This account has expired. Please contact your system administrator.")
+            }
+            Self::PasswordExpired => {
+                gettext("The password for this account has expired and has to be changed.")
+            }
+            Self::NotPermitted => gettext("This identity is not allowed to authorize the action."),
+            Self::AccountLocked => gettext("Authentication failed, the account is locked."),
+            Self::HelperUnavailable => {
+                gettext("Could not start authentication, see the logs for details.")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn classify(messages: &[&str]) -> FailureReason {
+        let messages: Vec<String> = messages.iter().map(|m| m.to_string()).collect();
+        FailureReason::classify(&messages)
+    }
+
+    #[test]
+    fn defaults_to_a_wrong_password() {
+        assert_eq!(classify(&[]), FailureReason::WrongPassword);
+        assert_eq!(
+            classify(&["Authentication failure"]),
+            FailureReason::WrongPassword
+        );
+    }
+
+    #[test]
+    fn recognises_lockouts() {
+        assert_eq!(
+            classify(&[
+                "The account is locked due to 3 failed logins.",
+                "(10 minutes left to unlock)"
+            ]),
+            FailureReason::AccountLocked
+        );
+        assert_eq!(
+            classify(&["Account temporary locked (600 seconds left)"]),
+            FailureReason::AccountLocked
+        );
+    }
+
+    #[test]
+    fn recognises_expired_accounts() {
+        assert_eq!(
+            classify(&["Your account has expired; please contact your system administrator."]),
+            FailureReason::AccountExpired
+        );
+    }
+
+    #[test]
+    fn recognises_expired_passwords() {
+        assert_eq!(
+            classify(&["You are required to change your password immediately (password expired)"]),
+            FailureReason::PasswordExpired
+        );
+        assert_eq!(
+            classify(&[
+                "You are required to change your password immediately (administrator enforced)"
+            ]),
+            FailureReason::PasswordExpired
+        );
+    }
+
+    #[test]
+    fn recognises_refusals() {
+        assert_eq!(classify(&["Access denied"]), FailureReason::NotPermitted);
+        assert_eq!(
+            classify(&["Permission denied"]),
+            FailureReason::NotPermitted
+        );
+    }
+
+    #[test]
+    fn prefers_the_most_specific_reason() {
+        assert_eq!(
+            classify(&[
+                "Authentication failure",
+                "Permission denied",
+                "The account is locked due to 3 failed logins."
+            ]),
+            FailureReason::AccountLocked
+        );
+        assert_eq!(
+            classify(&[
+                "Permission denied",
+                "Your account has expired; please contact your system administrator."
+            ]),
+            FailureReason::AccountExpired
+        );
+    }
+
+    #[test]
+    fn ignores_case() {
+        assert_eq!(
+            classify(&["YOUR ACCOUNT HAS EXPIRED"]),
+            FailureReason::AccountExpired
+        );
+    }
+}
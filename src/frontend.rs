@@ -0,0 +1,77 @@
+//! The boundary between the agent and whatever shows its requests to the user.
+//!
+//! The agent only ever talks to a [`FrontendProxy`], sending it [`AuthenticationAgentEvent`]s, and
+//! reads the user's [`AuthenticationUserEvent`]s from the matching receiver. A [`Frontend`] gets the
+//! other ends as a [`FrontendChannel`], so new frontends don't need any changes to the agent.
+
+use eyre::Result;
+use tokio::sync::mpsc;
+
+use crate::{
+    events::{AuthenticationAgentEvent, AuthenticationUserEvent},
+    external::ExternalFrontend,
+    future::BoxFuture,
+    tty::TtyFrontend,
+    ui::GtkFrontend,
+};
+
+/// Shows authentication requests to the user and reports their answers back to the agent.
+pub trait Frontend {
+    /// Resolves once the frontend quits. The dialog takes over the main thread for this, the others
+    /// run as tokio tasks like the agent.
+    fn run(self: Box<Self>, channel: FrontendChannel) -> BoxFuture<'static, Result<()>>;
+}
+
+/// The frontend's end of the connection to the agent.
+#[derive(Debug)]
+pub struct FrontendChannel {
+    pub events: mpsc::Receiver<AuthenticationAgentEvent>,
+    pub answers: mpsc::Sender<AuthenticationUserEvent>,
+}
+
+/// The agent's way of reaching the frontend.
+#[derive(Debug, Clone)]
+pub struct FrontendProxy {
+    events: mpsc::Sender<AuthenticationAgentEvent>,
+}
+
+impl FrontendProxy {
+    pub async fn send(&self, event: AuthenticationAgentEvent) {
+        if self.events.send(event).await.is_err() {
+            tracing::warn!("the frontend stopped listening, dropping event");
+        }
+    }
+}
+
+/// Connects the agent to a frontend, returning the agent's ends and the frontend's end.
+pub fn channel() -> (
+    FrontendProxy,
+    mpsc::Receiver<AuthenticationUserEvent>,
+    FrontendChannel,
+) {
+    let (event_sender, event_receiver) = mpsc::channel(32);
+    let (answer_sender, answer_receiver) = mpsc::channel(32);
+    (
+        FrontendProxy {
+            events: event_sender,
+        },
+        answer_receiver,
+        FrontendChannel {
+            events: event_receiver,
+            answers: answer_sender,
+        },
+    )
+}
+
+/// The shell's own prompt or the terminal when they were asked for, the terminal as well if there is
+/// no display, the dialog otherwise.
+pub fn select() -> Result<Box<dyn Frontend>> {
+    if ExternalFrontend::requested() {
+        Ok(Box::new(ExternalFrontend))
+    } else if TtyFrontend::requested() {
+        tracing::info!("answering authentication requests on the terminal");
+        Ok(Box::new(TtyFrontend))
+    } else {
+        Ok(Box::new(GtkFrontend::new()?))
+    }
+}
@@ -0,0 +1,5 @@
+//! Futures behind trait objects, for the helper backends and the frontends.
+
+use std::{future::Future, pin::Pin};
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
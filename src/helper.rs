@@ -0,0 +1,379 @@
+use std::{
+    fmt::Debug,
+    fs::File,
+    os::{
+        fd::AsRawFd,
+        unix::fs::{FileTypeExt, MetadataExt},
+    },
+    path::Path,
+    process::Stdio,
+};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, Lines},
+    net::UnixStream,
+    process,
+};
+
+use crate::{
+    authority::{PolkitError, Result},
+    config::SystemConfig,
+    future::BoxFuture,
+    protocol::HelperMessage,
+};
+
+/// Something that can start a conversation with `polkit-agent-helper-1` for a given user and cookie.
+pub trait HelperBackend: Debug + Send + Sync {
+    fn connect<'a>(
+        &'a self,
+        user: &'a str,
+        cookie: &'a str,
+    ) -> BoxFuture<'a, Result<HelperConnection>>;
+}
+
+/// Picks the socket backend if polkit provides one, otherwise spawns the setuid helper.
+pub fn from_config(config: &SystemConfig) -> Box<dyn HelperBackend> {
+    let socket_path = Path::new(config.get_socket_path());
+    if socket_path.exists() {
+        tracing::info!("using agent socket at {}", socket_path.display());
+        tracing::info!(
+            "the socket's helper runs in the system's locale, lockouts, expired passwords and why an attempt failed are only recognised in English"
+        );
+        Box::new(SocketBackend::new(config.get_socket_path()))
+    } else {
+        tracing::info!("using agent helper at {}", config.get_helper_path());
+        Box::new(SetuidBackend::new(
+            config.get_helper_path(),
+            config.get_allow_unsafe_helper(),
+        ))
+    }
+}
+
+/// A running conversation with the helper. Dropping it closes the socket or kills the helper.
+pub struct HelperConnection {
+    lines: Lines<BufReader<Box<dyn AsyncRead + Unpin + Send>>>,
+    writer: Box<dyn AsyncWrite + Unpin + Send>,
+    child: Option<process::Child>,
+}
+
+impl HelperConnection {
+    fn new(
+        reader: Box<dyn AsyncRead + Unpin + Send>,
+        writer: Box<dyn AsyncWrite + Unpin + Send>,
+        child: Option<process::Child>,
+    ) -> Self {
+        Self {
+            lines: BufReader::new(reader).lines(),
+            writer,
+            child,
+        }
+    }
+
+    /// Reads the next message the helper sent, or `None` once it hung up. This is cancel safe.
+    pub async fn next_message(&mut self) -> Result<Option<HelperMessage>> {
+        while let Some(line) = self.lines.next_line().await? {
+            tracing::debug!("helper stdout: {}", line);
+            match HelperMessage::parse(&line) {
+                Ok(message) => return Ok(Some(message)),
+                Err(e) => tracing::warn!("skipping malformed helper output: {}", e),
+            }
+        }
+        Ok(None)
+    }
+
+    pub async fn send_line(&mut self, line: &str) -> Result<()> {
+        self.writer.write_all(line.as_bytes()).await?;
+        self.writer.write_all(b"\n").await?;
+        self.writer.flush().await?;
+        Ok(())
+    }
+
+    /// Stops the helper without waiting for it to exit.
+    pub fn kill(&mut self) {
+        if let Some(child) = self.child.as_mut() {
+            let _ = child.start_kill();
+        }
+    }
+}
+
+/// Talks to polkit's socket activated helper, `/run/polkit/agent-helper.socket` by default.
+#[derive(Debug)]
+pub struct SocketBackend {
+    path: String,
+}
+
+impl SocketBackend {
+    pub fn new(path: impl Into<String>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// Checks that the socket is root's, in a directory only root can change.
+    ///
+    /// polkit's socket unit makes the socket writable by everyone on purpose: connecting needs write
+    /// permission, and every agent connects as its own user. Writing to a socket only connects to
+    /// whoever listens on it, to answer in polkit's place the socket has to be replaced, which takes
+    /// write access to its directory. So the directory has to be root's alone, and the socket has to
+    /// be root's like the unit creates it, rather than belong to another user or group. The peer is
+    /// checked again after connecting, in case the socket was swapped in between.
+    fn verify_socket(&self) -> Result<()> {
+        let path = Path::new(&self.path);
+        let refuse = |reason: &str| {
+            PolkitError::Failed(format!(
+                "Refusing to use the agent socket at {}, {reason}.",
+                self.path
+            ))
+        };
+
+        let socket = std::fs::symlink_metadata(path)?;
+        if !socket.file_type().is_socket() {
+            return Err(refuse("it is not a socket"));
+        }
+        if socket.uid() != 0 {
+            return Err(refuse("it is not owned by root"));
+        }
+        if socket.gid() != 0 && socket.mode() & 0o020 != 0 {
+            return Err(refuse("it is writable by a group other than root"));
+        }
+
+        let parent = path
+            .parent()
+            .ok_or_else(|| refuse("it has no parent directory"))?;
+        let directory = std::fs::metadata(parent)?;
+        if directory.uid() != 0 || directory.mode() & 0o022 != 0 {
+            return Err(refuse(
+                "its directory can be modified by users other than root",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl HelperBackend for SocketBackend {
+    fn connect<'a>(
+        &'a self,
+        user: &'a str,
+        cookie: &'a str,
+    ) -> BoxFuture<'a, Result<HelperConnection>> {
+        Box::pin(async move {
+            self.verify_socket()?;
+
+            let stream = UnixStream::connect(&self.path).await?;
+            // The path could have been swapped between the check and connecting, so also make sure
+            // that whoever is listening is root before they see the cookie or the password.
+            let peer = stream.peer_cred()?.uid();
+            if peer != 0 {
+                return Err(PolkitError::Failed(format!(
+                    "Refusing to use the agent socket at {}, it is served by uid {peer} instead of root.",
+                    self.path
+                )));
+            }
+
+            let (read_half, write_half) = stream.into_split();
+
+            let mut connection =
+                HelperConnection::new(Box::new(read_half), Box::new(write_half), None);
+            connection.send_line(user).await?;
+            connection.send_line(cookie).await?;
+
+            Ok(connection)
+        })
+    }
+}
+
+/// `PATH` for the helper, which otherwise runs with an empty environment.
+const HELPER_PATH_ENV: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// The locale the setuid helper runs in, so PAM's messages come untranslated. [`crate::lockout`],
+/// [`crate::failure`] and [`crate::password_change`] only understand those.
+///
+/// polkit starts the socket's helper itself, in the system's locale, so with [`SocketBackend`]
+/// they are only recognised if that is English. Otherwise every failure looks like a wrong
+/// password.
+pub const HELPER_LOCALE: &str = "C";
+
+/// Spawns the setuid `polkit-agent-helper-1` binary.
+#[derive(Debug)]
+pub struct SetuidBackend {
+    path: String,
+    allow_unsafe: bool,
+}
+
+impl SetuidBackend {
+    pub fn new(path: impl Into<String>, allow_unsafe: bool) -> Self {
+        Self {
+            path: path.into(),
+            allow_unsafe,
+        }
+    }
+
+    /// Opens the helper and checks that it is a setuid binary that only root can change. The path
+    /// may come from a user writable config file, and whatever it points at gets to see the password.
+    ///
+    /// The checks are made on the open file, which is what gets executed, so swapping the path
+    /// afterwards changes nothing.
+    fn verify_helper(&self) -> Result<File> {
+        let refuse = |reason: &str| {
+            PolkitError::Failed(format!(
+                "Refusing to use the helper at {}, {reason}. Set allow_unsafe_helper in /etc/soteria/config.toml to use it anyway.",
+                self.path
+            ))
+        };
+
+        let helper = File::open(&self.path)?;
+        let metadata = helper.metadata()?;
+        if !metadata.is_file() {
+            return Err(refuse("it is not a file"));
+        }
+        if metadata.uid() != 0 {
+            return Err(refuse("it is not owned by root"));
+        }
+        if metadata.mode() & 0o4000 == 0 {
+            return Err(refuse("it is not setuid"));
+        }
+        if metadata.mode() & 0o022 != 0 {
+            return Err(refuse("it is writable by group or others"));
+        }
+
+        Ok(helper)
+    }
+}
+
+impl HelperBackend for SetuidBackend {
+    fn connect<'a>(
+        &'a self,
+        user: &'a str,
+        cookie: &'a str,
+    ) -> BoxFuture<'a, Result<HelperConnection>> {
+        Box::pin(async move {
+            // Kept open until the helper has been spawned, the kernel resolves the descriptor's path
+            // before closing it on exec.
+            let helper;
+            let mut command = if self.allow_unsafe {
+                tracing::warn!("not verifying the helper at {}", self.path);
+                process::Command::new(&self.path)
+            } else {
+                helper = self.verify_helper()?;
+                let mut command =
+                    process::Command::new(format!("/proc/self/fd/{}", helper.as_raw_fd()));
+                command.arg0(&self.path);
+                command
+            };
+
+            let mut child = command
+                .arg(user)
+                .env_clear()
+                .env("PATH", HELPER_PATH_ENV)
+                .env("LC_ALL", HELPER_LOCALE)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .kill_on_drop(true)
+                .spawn()
+                .map_err(|_| {
+                    PolkitError::Failed(
+                        "Failed to the spawn polkit authentication helper.".to_string(),
+                    )
+                })?;
+
+            let stdin = child
+                .stdin
+                .take()
+                .ok_or(PolkitError::Failed("Child did not have stdin.".to_string()))?;
+            let stdout = child.stdout.take().ok_or(PolkitError::Failed(
+                "Child did not have stdout.".to_string(),
+            ))?;
+
+            let mut connection =
+                HelperConnection::new(Box::new(stdout), Box::new(stdin), Some(child));
+            connection.send_line(cookie).await?;
+
+            Ok(connection)
+        })
+    }
+}
+
+/// One step of a scripted [`MockBackend`] conversation.
+#[cfg(test)]
+#[derive(Debug, Clone)]
+pub enum MockStep {
+    /// The helper writes this message to the agent.
+    Send(HelperMessage),
+    /// The helper reads a line from the agent and checks that it matches.
+    Expect(String),
+}
+
+/// An in-process helper that plays back a script, so the conversation logic can be exercised
+/// without root or a real PAM stack.
+#[cfg(test)]
+#[derive(Debug, Clone)]
+pub struct MockBackend {
+    script: Vec<MockStep>,
+}
+
+#[cfg(test)]
+impl MockBackend {
+    pub fn new(script: Vec<MockStep>) -> Self {
+        Self { script }
+    }
+}
+
+#[cfg(test)]
+impl HelperBackend for MockBackend {
+    fn connect<'a>(
+        &'a self,
+        user: &'a str,
+        cookie: &'a str,
+    ) -> BoxFuture<'a, Result<HelperConnection>> {
+        Box::pin(async move {
+            let (agent_side, helper_side) = tokio::io::duplex(4096);
+            let (agent_read, agent_write) = tokio::io::split(agent_side);
+            let (helper_read, mut helper_write) = tokio::io::split(helper_side);
+
+            let mut script = vec![
+                MockStep::Expect(user.to_string()),
+                MockStep::Expect(cookie.to_string()),
+            ];
+            script.extend(self.script.iter().cloned());
+
+            tokio::spawn(async move {
+                let mut lines = BufReader::new(helper_read).lines();
+                for step in script {
+                    match step {
+                        MockStep::Send(message) => {
+                            let written = async {
+                                helper_write
+                                    .write_all(message.to_string().as_bytes())
+                                    .await?;
+                                helper_write.write_all(b"\n").await
+                            };
+                            if written.await.is_err() {
+                                return;
+                            }
+                        }
+                        MockStep::Expect(expected) => match lines.next_line().await {
+                            Ok(Some(line)) if line == expected => {}
+                            other => {
+                                tracing::error!(
+                                    "mock helper expected {:?}, got {:?}",
+                                    expected,
+                                    other
+                                );
+                                let failure = format!("{}\n", HelperMessage::Failure);
+                                let _ = helper_write.write_all(failure.as_bytes()).await;
+                                return;
+                            }
+                        },
+                    }
+                }
+            });
+
+            let mut connection =
+                HelperConnection::new(Box::new(agent_read), Box::new(agent_write), None);
+            // The mock expects the same preamble as the socket backend.
+            connection.send_line(user).await?;
+            connection.send_line(cookie).await?;
+
+            Ok(connection)
+        })
+    }
+}
@@ -0,0 +1,225 @@
+use std::{
+    ffi::{CStr, CString},
+    path::Path,
+};
+
+use zbus::Connection;
+
+use crate::accounts::{AccountsProxy, UserProxy};
+
+/// The kind of polkit identity an entry in the dialog came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdentityKind {
+    UnixUser,
+    UnixGroup,
+}
+
+/// An identity polkit offered, with everything needed to show it in the dialog.
+///
+/// Unix groups are expanded into their members, which keep their own uid and remember the group.
+/// Identities that can't be resolved are still kept, and shown by their uid or gid.
+#[derive(Debug, Clone, zeroize::Zeroize)]
+pub struct UserIdentity {
+    #[zeroize(skip)]
+    pub kind: IdentityKind,
+    /// Unset only for a group that could not be expanded into members.
+    pub uid: Option<u32>,
+    /// The gid of the group this identity was offered through.
+    pub gid: Option<u32>,
+    pub username: Option<String>,
+    /// The full name from AccountsService or the GECOS field, if the user has one.
+    pub display_name: Option<String>,
+    /// Path to the user's avatar image.
+    pub avatar: Option<String>,
+    /// The group this user was offered through, if polkit asked for a `unix-group`.
+    pub group: Option<String>,
+}
+
+impl UserIdentity {
+    /// Whether PAM can be started for this identity. Users and groups that could not be resolved
+    /// are still shown, but there is no name to hand to the helper for them.
+    pub fn selectable(&self) -> bool {
+        self.username.is_some()
+    }
+
+    /// How to refer to this identity when nothing else is known, e.g. "uid 1001".
+    pub fn fallback_label(&self) -> String {
+        match (self.uid, self.gid) {
+            (Some(uid), _) => format!("uid {uid}"),
+            (None, Some(gid)) => format!("gid {gid}"),
+            (None, None) => String::new(),
+        }
+    }
+
+    /// The text shown for this identity, e.g. "Jane Doe (jdoe)".
+    pub fn label(&self) -> String {
+        let label = match (&self.display_name, &self.username) {
+            (Some(name), Some(username)) if name != username => format!("{name} ({username})"),
+            (_, Some(username)) => username.clone(),
+            (Some(name), None) => name.clone(),
+            (None, None) => self.fallback_label(),
+        };
+        match (&self.group, self.kind) {
+            // A group without members is shown as the group itself.
+            (Some(group), IdentityKind::UnixGroup) if self.uid.is_none() => {
+                format!("{group} ({label})")
+            }
+            (Some(group), _) => format!("{label} · {group}"),
+            (None, _) => label,
+        }
+    }
+
+    /// Looks up `uid` in the passwd database, and AccountsService when it is available.
+    pub async fn from_uid(connection: &Connection, uid: u32) -> Self {
+        let mut identity = Self {
+            kind: IdentityKind::UnixUser,
+            uid: Some(uid),
+            gid: None,
+            username: None,
+            display_name: None,
+            avatar: None,
+            group: None,
+        };
+
+        let Some(passwd) = etc_passwd::Passwd::from_uid(uid).ok().flatten() else {
+            tracing::warn!("could not find a user with uid {}", uid);
+            return identity;
+        };
+        identity.username = passwd.name.to_str().ok().map(str::to_string);
+
+        let (real_name, icon_file) = match accounts_user(connection, uid).await {
+            Ok(user) => user,
+            Err(e) => {
+                tracing::debug!("could not query AccountsService for uid {}: {}", uid, e);
+                (None, None)
+            }
+        };
+
+        identity.display_name = real_name.or_else(|| {
+            passwd
+                .gecos
+                .to_str()
+                .ok()
+                .and_then(|gecos| gecos.split(',').next())
+                .filter(|name| !name.is_empty())
+                .map(str::to_string)
+        });
+
+        identity.avatar = icon_file.or_else(|| {
+            passwd
+                .dir
+                .to_str()
+                .ok()
+                .map(|home| Path::new(home).join(".face"))
+                .filter(|face| face.is_file())
+                .map(|face| face.display().to_string())
+        });
+
+        identity
+    }
+
+    /// Resolves every member of the group `gid`, remembering the group they came from.
+    pub async fn from_gid(connection: &Connection, gid: u32) -> Vec<Self> {
+        let (group, members) = match group_members(gid) {
+            Some((group, members)) => (Some(group), members),
+            None => {
+                tracing::warn!("could not find a group with gid {}", gid);
+                (None, Vec::new())
+            }
+        };
+
+        let mut users = Vec::new();
+        for member in members {
+            let uid = CString::new(member)
+                .ok()
+                .and_then(|name| etc_passwd::Passwd::from_name(name).ok().flatten())
+                .map(|passwd| passwd.uid);
+            if let Some(uid) = uid {
+                let mut user = Self::from_uid(connection, uid).await;
+                user.kind = IdentityKind::UnixGroup;
+                user.gid = Some(gid);
+                user.group = group.clone();
+                users.push(user);
+            }
+        }
+
+        if users.is_empty() {
+            users.push(Self {
+                kind: IdentityKind::UnixGroup,
+                uid: None,
+                gid: Some(gid),
+                username: None,
+                display_name: None,
+                avatar: None,
+                group,
+            });
+        }
+        users
+    }
+}
+
+/// The identity the dialog starts with: the user running the agent if they are offered, otherwise
+/// the first one that can be selected.
+pub fn default_index(identities: &[UserIdentity]) -> usize {
+    let current = etc_passwd::Passwd::current_user()
+        .ok()
+        .flatten()
+        .and_then(|p| p.name.into_string().ok());
+    current
+        .and_then(|current| {
+            identities
+                .iter()
+                .position(|i| i.username.as_ref() == Some(&current))
+        })
+        .or_else(|| identities.iter().position(UserIdentity::selectable))
+        .unwrap_or(0)
+}
+
+/// The name and listed members of the group `gid`. Like polkit, this only considers users that
+/// have it as a supplementary group.
+fn group_members(gid: u32) -> Option<(String, Vec<String>)> {
+    // SAFETY: `group` is only read after getgrgid_r reported success, its pointers point into
+    // `buf`, which outlives every read below.
+    unsafe {
+        let mut group: libc::group = std::mem::zeroed();
+        let mut result: *mut libc::group = std::ptr::null_mut();
+        let mut buf: Vec<libc::c_char> = vec![0; 1024];
+
+        loop {
+            let ret = libc::getgrgid_r(gid, &mut group, buf.as_mut_ptr(), buf.len(), &mut result);
+            match ret {
+                libc::ERANGE => buf.resize(buf.len() * 2, 0),
+                0 if !result.is_null() => break,
+                _ => return None,
+            }
+        }
+
+        let name = CStr::from_ptr(group.gr_name).to_string_lossy().into_owned();
+        let mut members = Vec::new();
+        let mut member = group.gr_mem;
+        while !member.is_null() && !(*member).is_null() {
+            members.push(CStr::from_ptr(*member).to_string_lossy().into_owned());
+            member = member.add(1);
+        }
+
+        Some((name, members))
+    }
+}
+
+/// The real name and icon AccountsService has for `uid`, ignoring empty or missing values.
+async fn accounts_user(
+    connection: &Connection,
+    uid: u32,
+) -> zbus::Result<(Option<String>, Option<String>)> {
+    let accounts = AccountsProxy::new(connection).await?;
+    let path = accounts.find_user_by_id(uid.into()).await?;
+    let user = UserProxy::builder(connection).path(path)?.build().await?;
+
+    let real_name = user.real_name().await?;
+    let icon_file = user.icon_file().await?;
+
+    Ok((
+        Some(real_name).filter(|name| !name.is_empty()),
+        Some(icon_file).filter(|icon| Path::new(icon).is_file()),
+    ))
+}
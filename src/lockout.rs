@@ -0,0 +1,145 @@
+//! Detects account lockouts from the messages pam_faillock, pam_tally2 and pam_tally send.
+//!
+//! Only the untranslated messages are understood, see [`crate::helper::HELPER_LOCALE`]:
+//!
+//! * pam_faillock: `The account is locked due to 3 failed logins.` followed by
+//!   `(10 minutes left to unlock)`
+//! * pam_tally2: `The account is temporarily locked (600 seconds left).` or
+//!   `The account is locked due to 3 failed logins.`
+//! * older pam_tally2 and pam_tally: `Account temporary locked (600 seconds left)` or
+//!   `Account locked due to 3 failed logins`
+
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lockout {
+    /// The account unlocks again after this long.
+    For(Duration),
+    /// The account is locked, but the module did not say for how long.
+    Indefinite,
+}
+
+impl Lockout {
+    pub fn parse(message: &str) -> Option<Self> {
+        if let Some(lockout) = remaining(message) {
+            return Some(lockout);
+        }
+
+        let message = message.to_ascii_lowercase();
+        ["locked due to", "temporarily locked", "temporary locked"]
+            .iter()
+            .any(|needle| message.contains(needle))
+            .then_some(Self::Indefinite)
+    }
+}
+
+/// Parses the `(<n> <unit> left ...)` part of a lockout message. A time too long to represent is
+/// treated like no time at all.
+fn remaining(message: &str) -> Option<Lockout> {
+    let start = message.rfind('(')?;
+    let inner = message[start + 1..].trim_end_matches([')', '.']);
+    let mut words = inner.split_whitespace();
+
+    let amount = words.next()?;
+    if !amount.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let unit = words.next()?;
+    if words.next()? != "left" {
+        return None;
+    }
+
+    let multiplier: u64 = match unit.trim_end_matches('s') {
+        "second" => 1,
+        "minute" => 60,
+        "hour" => 60 * 60,
+        _ => return None,
+    };
+    let lockout = amount
+        .parse::<u64>()
+        .ok()
+        .and_then(|amount| amount.checked_mul(multiplier))
+        .map_or(Lockout::Indefinite, |seconds| {
+            Lockout::For(Duration::from_secs(seconds))
+        });
+    Some(lockout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn minutes(n: u64) -> Option<Lockout> {
+        Some(Lockout::For(Duration::from_secs(n * 60)))
+    }
+
+    #[test]
+    fn parses_pam_faillock() {
+        assert_eq!(
+            Lockout::parse("The account is locked due to 3 failed logins."),
+            Some(Lockout::Indefinite)
+        );
+        assert_eq!(Lockout::parse("(10 minutes left to unlock)"), minutes(10));
+        assert_eq!(Lockout::parse("(1 minutes left to unlock)"), minutes(1));
+    }
+
+    #[test]
+    fn parses_pam_tally2() {
+        assert_eq!(
+            Lockout::parse("The account is temporarily locked (600 seconds left)."),
+            minutes(10)
+        );
+        assert_eq!(
+            Lockout::parse("The account is locked due to 5 failed logins."),
+            Some(Lockout::Indefinite)
+        );
+    }
+
+    #[test]
+    fn parses_older_pam_tally() {
+        assert_eq!(
+            Lockout::parse("Account temporary locked (600 seconds left)"),
+            minutes(10)
+        );
+        assert_eq!(
+            Lockout::parse("Account temporarily locked (600 seconds left)"),
+            minutes(10)
+        );
+        assert_eq!(
+            Lockout::parse("Account locked due to 3 failed logins"),
+            Some(Lockout::Indefinite)
+        );
+    }
+
+    #[test]
+    fn parses_hours() {
+        assert_eq!(Lockout::parse("(2 hours left to unlock)"), minutes(120));
+    }
+
+    #[test]
+    fn treats_huge_times_as_indefinite() {
+        assert_eq!(
+            Lockout::parse("(18446744073709551615 hours left to unlock)"),
+            Some(Lockout::Indefinite)
+        );
+        assert_eq!(
+            Lockout::parse("(99999999999999999999999 seconds left to unlock)"),
+            Some(Lockout::Indefinite)
+        );
+    }
+
+    #[test]
+    fn ignores_other_messages() {
+        for message in [
+            "Password:",
+            "Authentication failure",
+            "(not a number left to unlock)",
+            "(10 fortnights left to unlock)",
+            "(-5 minutes left to unlock)",
+            "Your account has expired; please contact your system administrator.",
+            "",
+        ] {
+            assert_eq!(Lockout::parse(message), None, "{message:?}");
+        }
+    }
+}
@@ -1,26 +1,35 @@
+use actions::ActionCache;
 use authority::{AuthorityProxy, Subject};
 use dbus::AuthenticationAgent;
 use eyre::{Result, WrapErr, ensure};
 use futures_lite::StreamExt;
-use gtk4::glib::{clone, spawn_future_local};
-use relm4::RelmApp;
 use std::collections::HashMap;
 use std::path::Path;
-use tokio::sync::mpsc::channel;
 use tracing::level_filters::LevelFilter;
 use zbus::zvariant::Value;
 
 use zbus::conn;
 
 use crate::config::SystemConfig;
-use crate::events::{AuthenticationAgentEvent, AuthenticationUserEvent};
-use crate::ui::App;
 
+mod accounts;
+mod actions;
 mod authority;
 mod config;
 mod constants;
 mod dbus;
 mod events;
+mod external;
+mod failure;
+mod frontend;
+mod future;
+mod helper;
+mod identity;
+mod lockout;
+mod password_change;
+mod protocol;
+mod session;
+mod tty;
 mod ui;
 
 use gettextrs::{bindtextdomain, textdomain};
@@ -52,13 +61,8 @@ async fn main() -> Result<()> {
     bindtextdomain("soteria", &locale_path)?;
     textdomain("soteria")?;
 
-    let config_path = std::env::var("XDG_CONFIG_HOME")
-        .or(std::env::var("HOME").map(|e| e + "/.config"))
-        .context("Could not resolve configuration path")?;
-    let css_path = format!("{config_path}/soteria/style.css");
-    let path = Path::new(&css_path);
-
     let config: SystemConfig = SystemConfig::from_file()?;
+    let frontend = frontend::select()?;
 
     let agent_helper = Path::new(config.get_helper_path());
     let agent_socket = Path::new(config.get_socket_path());
@@ -70,14 +74,7 @@ async fn main() -> Result<()> {
         &agent_socket.display()
     );
 
-    if agent_socket.exists() {
-        tracing::info!("using agent socket at {}", &agent_socket.display());
-    } else {
-        tracing::info!("using agent helper at {}", &agent_helper.display());
-    }
-
-    let (agent_sender, agent_receiver) = channel::<AuthenticationAgentEvent>(32);
-    let (user_sender, user_receiver) = channel::<AuthenticationUserEvent>(32);
+    let (frontend_proxy, answers, frontend_channel) = frontend::channel();
 
     let locale = gtk4::glib::language_names()[0].as_str().to_string();
     tracing::info!("Registering authentication agent with locale: {}", locale);
@@ -92,7 +89,8 @@ async fn main() -> Result<()> {
     )]);
     let subject = Subject::new(subject_kind, subject_details);
 
-    let agent = AuthenticationAgent::new(agent_sender, user_receiver, config.clone());
+    let actions = ActionCache::default();
+    let agent = AuthenticationAgent::new(frontend_proxy, answers, config.clone(), actions.clone());
     let connection = conn::Builder::system()?
         .serve_at(constants::SELF_OBJECT_PATH, agent)?
         .build()
@@ -105,12 +103,33 @@ async fn main() -> Result<()> {
 
     tracing::info!("Registered as authentication agent.");
 
+    if let Err(e) = actions.load(&proxy, &locale).await {
+        tracing::warn!("Could not load action descriptions: {}", e);
+    }
+
+    let mut changed_signal = proxy
+        .receive_changed()
+        .await
+        .context("Could not get the signal for authority changes?")?;
+    tokio::spawn({
+        let proxy = proxy.clone();
+        let locale = locale.clone();
+        async move {
+            while changed_signal.next().await.is_some() {
+                tracing::info!("Polkit's configuration changed, reloading action descriptions.");
+                if let Err(e) = actions.load(&proxy, &locale).await {
+                    tracing::warn!("Could not reload action descriptions: {}", e);
+                }
+            }
+        }
+    });
+
     let mut owner_change_signal = proxy
         .inner()
         .receive_owner_changed()
         .await
         .context("Could not get the signal for owner change?")?;
-    spawn_future_local(clone!(async move {
+    tokio::spawn(async move {
         while let Some(result) = owner_change_signal.next().await {
             // if we can get a UniqueName out of the stream, then polkit has restarted for some reason & we should reregister
             if result.is_some() {
@@ -125,15 +144,7 @@ async fn main() -> Result<()> {
                 tracing::info!("Reregistered as authentication agent.")
             }
         }
-    }));
+    });
 
-    let app = RelmApp::new("gay.vaskel.soteria");
-    if path.is_file() {
-        tracing::info!("loading css stylesheet from {}", css_path);
-        relm4::set_global_css_from_file(path)
-            .context("Could not load CSS stylesheet for some reason")?;
-    }
-    app.run_async::<App>((user_sender, agent_receiver));
-
-    Ok(())
+    frontend.run(frontend_channel).await
 }
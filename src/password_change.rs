@@ -0,0 +1,113 @@
+//! Recognises the prompts `pam_chauthtok` sends when an expired password has to be changed.
+//!
+//! pam_unix asks for `Password:` when authenticating, and pam_unix and pam_pwquality ask for
+//! `Current password:`, `New password:` and `Retype new password:` when changing it. Only these
+//! untranslated prompts are understood, see [`crate::helper::HELPER_LOCALE`].
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SecretPrompt {
+    /// The account's password, asked to authenticate.
+    Password,
+    /// The password the user already typed, asked again before it can be changed.
+    Current,
+    New,
+    /// The new password once more, to catch typos.
+    Retype,
+    /// Anything else, like a one time code, which the user has to answer themselves.
+    Other,
+}
+
+impl SecretPrompt {
+    /// Only looks at how the prompt starts, prompts from other modules often end with the username,
+    /// and a user called goldberg or newton must not turn a one time code into the old password or
+    /// a login into a password change.
+    pub fn classify(prompt: &str) -> Self {
+        let prompt = prompt.trim_start().to_ascii_lowercase();
+        let starts_with = |prefixes: &[&str]| prefixes.iter().any(|p| prompt.starts_with(p));
+        if starts_with(&["retype new ", "repeat new "]) {
+            Self::Retype
+        } else if starts_with(&[
+            "new password",
+            "new unix password",
+            "enter new password",
+            "enter new unix password",
+        ]) {
+            Self::New
+        } else if starts_with(&["current password", "(current)", "old password"]) {
+            Self::Current
+        } else if starts_with(&["password"]) {
+            Self::Password
+        } else {
+            Self::Other
+        }
+    }
+
+    /// Whether the answer is the account's current password, which PAM may ask for again.
+    pub fn is_current_password(self) -> bool {
+        matches!(self, Self::Password | Self::Current)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SecretPrompt;
+
+    #[test]
+    fn recognises_pam_unix_and_pwquality() {
+        assert_eq!(SecretPrompt::classify("Password: "), SecretPrompt::Password);
+        assert_eq!(
+            SecretPrompt::classify("Current password: "),
+            SecretPrompt::Current
+        );
+        assert_eq!(
+            SecretPrompt::classify("(current) UNIX password: "),
+            SecretPrompt::Current
+        );
+        assert_eq!(SecretPrompt::classify("New password: "), SecretPrompt::New);
+        assert_eq!(
+            SecretPrompt::classify("Enter new UNIX password: "),
+            SecretPrompt::New
+        );
+        assert_eq!(
+            SecretPrompt::classify("Retype new password: "),
+            SecretPrompt::Retype
+        );
+        assert_eq!(
+            SecretPrompt::classify("Repeat new password: "),
+            SecretPrompt::Retype
+        );
+    }
+
+    #[test]
+    fn ignores_usernames_in_prompts() {
+        assert_eq!(
+            SecretPrompt::classify("Password for newton: "),
+            SecretPrompt::Password
+        );
+        assert_eq!(
+            SecretPrompt::classify("Password for goldberg: "),
+            SecretPrompt::Password
+        );
+        assert_eq!(
+            SecretPrompt::classify("One-time password (OATH) for `goldberg': "),
+            SecretPrompt::Other
+        );
+        assert_eq!(
+            SecretPrompt::classify("One-time password (OATH) for `newton': "),
+            SecretPrompt::Other
+        );
+    }
+
+    #[test]
+    fn leaves_other_questions_to_the_user() {
+        assert_eq!(
+            SecretPrompt::classify("Verification code: "),
+            SecretPrompt::Other
+        );
+        assert_eq!(
+            SecretPrompt::classify("PIN for renewal: "),
+            SecretPrompt::Other
+        );
+        assert_eq!(SecretPrompt::classify(""), SecretPrompt::Other);
+    }
+}
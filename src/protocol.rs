@@ -0,0 +1,337 @@
+//! Parsing for the line based protocol spoken by `polkit-agent-helper-1`.
+//!
+//! Each line the helper writes is either a PAM conversation message, followed by its text escaped
+//! the same way `g_strescape` does, or the final `SUCCESS`/`FAILURE` verdict.
+
+use std::fmt::{self, Display, Write};
+
+const PROMPT_ECHO_OFF: &str = "PAM_PROMPT_ECHO_OFF";
+const PROMPT_ECHO_ON: &str = "PAM_PROMPT_ECHO_ON";
+const ERROR_MSG: &str = "PAM_ERROR_MSG";
+const TEXT_INFO: &str = "PAM_TEXT_INFO";
+const SUCCESS: &str = "SUCCESS";
+const FAILURE: &str = "FAILURE";
+
+/// A single line of helper output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HelperMessage {
+    /// PAM asks for something that should not be shown while typing, usually a password.
+    PromptEchoOff(String),
+    /// PAM asks for something that can be shown while typing, like a username or OTP.
+    PromptEchoOn(String),
+    /// Informational text, like "Place your finger on the reader".
+    TextInfo(String),
+    /// Error text, like "Your account has expired".
+    ErrorMsg(String),
+    Success,
+    Failure,
+    /// A line we don't know about. Newer helpers may send these, so they are not an error.
+    Unknown(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolError {
+    /// The helper wrote an empty line.
+    Empty,
+    /// A message keyword was not followed by a space and its text.
+    MissingText(&'static str),
+    /// The text ended in the middle of an escape sequence.
+    TrailingBackslash,
+    /// The text contained an escape sequence we can't decode.
+    InvalidEscape(char),
+}
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => write!(f, "helper sent an empty line"),
+            Self::MissingText(keyword) => write!(f, "{keyword} was not followed by any text"),
+            Self::TrailingBackslash => write!(f, "message ended with an unfinished escape"),
+            Self::InvalidEscape(c) => write!(f, "message contained an invalid escape '\\{c}'"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl HelperMessage {
+    pub fn parse(line: &str) -> Result<Self, ProtocolError> {
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.trim().is_empty() {
+            return Err(ProtocolError::Empty);
+        }
+
+        for (keyword, build) in [
+            (PROMPT_ECHO_OFF, Self::PromptEchoOff as fn(String) -> Self),
+            (PROMPT_ECHO_ON, Self::PromptEchoOn),
+            (ERROR_MSG, Self::ErrorMsg),
+            (TEXT_INFO, Self::TextInfo),
+        ] {
+            let Some(rest) = line.strip_prefix(keyword) else {
+                continue;
+            };
+            return match rest.strip_prefix(' ') {
+                Some(text) => Ok(build(unescape(text.trim())?)),
+                // PAM is allowed to send an empty prompt, the helper still writes the keyword.
+                None if rest.trim().is_empty() => Ok(build(String::new())),
+                None => Err(ProtocolError::MissingText(keyword)),
+            };
+        }
+
+        match line.trim() {
+            SUCCESS => Ok(Self::Success),
+            FAILURE => Ok(Self::Failure),
+            _ => Ok(Self::Unknown(line.to_string())),
+        }
+    }
+}
+
+/// Writes the message back out in the format the helper uses.
+impl Display for HelperMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (keyword, text) = match self {
+            Self::PromptEchoOff(text) => (PROMPT_ECHO_OFF, text),
+            Self::PromptEchoOn(text) => (PROMPT_ECHO_ON, text),
+            Self::ErrorMsg(text) => (ERROR_MSG, text),
+            Self::TextInfo(text) => (TEXT_INFO, text),
+            Self::Success => return f.write_str(SUCCESS),
+            Self::Failure => return f.write_str(FAILURE),
+            Self::Unknown(line) => return f.write_str(line),
+        };
+        write!(f, "{keyword} ")?;
+        escape(text, f)
+    }
+}
+
+/// Reverses `g_strescape`, which is what `g_strcompress` does on polkit's side.
+fn unescape(text: &str) -> Result<String, ProtocolError> {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+
+        match chars.next().ok_or(ProtocolError::TrailingBackslash)? {
+            'b' => out.push('\u{8}'),
+            'f' => out.push('\u{c}'),
+            'n' => out.push('\n'),
+            'r' => out.push('\r'),
+            't' => out.push('\t'),
+            'v' => out.push('\u{b}'),
+            '\\' => out.push('\\'),
+            '"' => out.push('"'),
+            first @ '0'..='7' => {
+                // Up to three octal digits encode one byte, which may be part of a UTF-8 sequence.
+                let mut value = first.to_digit(8).unwrap();
+                for _ in 0..2 {
+                    match chars.peek().and_then(|c| c.to_digit(8)) {
+                        Some(digit) => {
+                            value = value * 8 + digit;
+                            chars.next();
+                        }
+                        None => break,
+                    }
+                }
+                let mut bytes = vec![(value & 0xff) as u8];
+                while chars.peek() == Some(&'\\') {
+                    let mut ahead = chars.clone();
+                    ahead.next();
+                    let digits: String = ahead.take_while(|c| c.is_digit(8)).take(3).collect();
+                    if digits.len() != 3 {
+                        break;
+                    }
+                    chars.nth(3);
+                    bytes.push((u32::from_str_radix(&digits, 8).unwrap() & 0xff) as u8);
+                }
+                out.push_str(&String::from_utf8_lossy(&bytes));
+            }
+            other => return Err(ProtocolError::InvalidEscape(other)),
+        }
+    }
+
+    Ok(out)
+}
+
+fn escape(text: &str, f: &mut impl Write) -> fmt::Result {
+    for c in text.chars() {
+        match c {
+            '\u{8}' => f.write_str("\\b")?,
+            '\u{c}' => f.write_str("\\f")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            '\u{b}' => f.write_str("\\v")?,
+            '\\' => f.write_str("\\\\")?,
+            '"' => f.write_str("\\\"")?,
+            c if (c as u32) < 0x20 || c as u32 == 0x7f => write!(f, "\\{:03o}", c as u32)?,
+            c => f.write_char(c)?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_every_keyword() {
+        let cases = [
+            (
+                "PAM_PROMPT_ECHO_OFF Password:",
+                HelperMessage::PromptEchoOff("Password:".to_string()),
+            ),
+            (
+                "PAM_PROMPT_ECHO_ON Login:",
+                HelperMessage::PromptEchoOn("Login:".to_string()),
+            ),
+            (
+                "PAM_ERROR_MSG Your account has expired",
+                HelperMessage::ErrorMsg("Your account has expired".to_string()),
+            ),
+            (
+                "PAM_TEXT_INFO Place your finger on the reader",
+                HelperMessage::TextInfo("Place your finger on the reader".to_string()),
+            ),
+            ("SUCCESS", HelperMessage::Success),
+            ("FAILURE", HelperMessage::Failure),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(HelperMessage::parse(line), Ok(expected), "{line}");
+        }
+    }
+
+    #[test]
+    fn allows_empty_prompts() {
+        for line in [
+            "PAM_PROMPT_ECHO_OFF",
+            "PAM_PROMPT_ECHO_OFF ",
+            "PAM_PROMPT_ECHO_OFF  ",
+        ] {
+            assert_eq!(
+                HelperMessage::parse(line),
+                Ok(HelperMessage::PromptEchoOff(String::new())),
+                "{line:?}"
+            );
+        }
+        assert_eq!(
+            HelperMessage::parse("PAM_TEXT_INFO"),
+            Ok(HelperMessage::TextInfo(String::new()))
+        );
+    }
+
+    #[test]
+    fn trims_line_endings() {
+        assert_eq!(
+            HelperMessage::parse("PAM_PROMPT_ECHO_OFF Password: \r\n"),
+            Ok(HelperMessage::PromptEchoOff("Password:".to_string()))
+        );
+        assert_eq!(
+            HelperMessage::parse("SUCCESS\r\n"),
+            Ok(HelperMessage::Success)
+        );
+        assert_eq!(
+            HelperMessage::parse("FAILURE\n"),
+            Ok(HelperMessage::Failure)
+        );
+    }
+
+    #[test]
+    fn rejects_empty_lines() {
+        for line in ["", "\n", "\r\n", "   "] {
+            assert_eq!(
+                HelperMessage::parse(line),
+                Err(ProtocolError::Empty),
+                "{line:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_keywords_glued_to_their_text() {
+        assert_eq!(
+            HelperMessage::parse("PAM_PROMPT_ECHO_OFFPassword:"),
+            Err(ProtocolError::MissingText(PROMPT_ECHO_OFF))
+        );
+        assert_eq!(
+            HelperMessage::parse("PAM_ERROR_MSG\tsomething"),
+            Err(ProtocolError::MissingText(ERROR_MSG))
+        );
+    }
+
+    #[test]
+    fn rejects_broken_escapes() {
+        assert_eq!(
+            HelperMessage::parse("PAM_TEXT_INFO trailing \\"),
+            Err(ProtocolError::TrailingBackslash)
+        );
+        assert_eq!(
+            HelperMessage::parse("PAM_TEXT_INFO bad \\q escape"),
+            Err(ProtocolError::InvalidEscape('q'))
+        );
+    }
+
+    #[test]
+    fn unescapes_like_g_strcompress() {
+        assert_eq!(
+            HelperMessage::parse(r#"PAM_TEXT_INFO a\tb\nc\\d\"e\bf\fg\rh\vi"#),
+            Ok(HelperMessage::TextInfo(
+                "a\tb\nc\\d\"e\u{8}f\u{c}g\rh\u{b}i".to_string()
+            ))
+        );
+        assert_eq!(
+            HelperMessage::parse(r"PAM_TEXT_INFO bell\007 and \1"),
+            Ok(HelperMessage::TextInfo("bell\u{7} and \u{1}".to_string()))
+        );
+    }
+
+    #[test]
+    fn joins_multi_byte_octal_escapes() {
+        // g_strescape writes every byte of a non-ASCII character as its own octal escape.
+        assert_eq!(
+            HelperMessage::parse(r"PAM_PROMPT_ECHO_OFF Contrase\303\261a:"),
+            Ok(HelperMessage::PromptEchoOff("Contraseña:".to_string()))
+        );
+        assert_eq!(
+            HelperMessage::parse(r"PAM_TEXT_INFO \342\234\223 done"),
+            Ok(HelperMessage::TextInfo("✓ done".to_string()))
+        );
+    }
+
+    #[test]
+    fn passes_unknown_lines_through() {
+        assert_eq!(
+            HelperMessage::parse("PAM_SOMETHING_NEW with text"),
+            Ok(HelperMessage::Unknown(
+                "PAM_SOMETHING_NEW with text".to_string()
+            ))
+        );
+        assert_eq!(
+            HelperMessage::parse("SUCCESSFUL"),
+            Ok(HelperMessage::Unknown("SUCCESSFUL".to_string()))
+        );
+    }
+
+    #[test]
+    fn display_round_trips() {
+        let messages = [
+            HelperMessage::PromptEchoOff("Password:".to_string()),
+            HelperMessage::PromptEchoOn("One-time code:".to_string()),
+            HelperMessage::ErrorMsg("line one\nline \"two\"\t\\".to_string()),
+            HelperMessage::TextInfo("Contraseña \u{7}".to_string()),
+            HelperMessage::Success,
+            HelperMessage::Failure,
+            HelperMessage::Unknown("PAM_SOMETHING_NEW text".to_string()),
+        ];
+        for message in messages {
+            assert_eq!(
+                HelperMessage::parse(&message.to_string()),
+                Ok(message.clone()),
+                "{message:?}"
+            );
+        }
+    }
+}
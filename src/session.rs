@@ -0,0 +1,210 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{sync::mpsc, time::Instant};
+
+use crate::{
+    authority::{PolkitError, Result},
+    events::{AuthenticationAgentEvent, AuthenticationUserEvent},
+    frontend::FrontendProxy,
+};
+
+/// How long before the idle timeout the frontend is warned about it.
+const IDLE_WARNING: Duration = Duration::from_secs(30);
+
+/// Keeps track of every authentication request that is currently in flight, keyed by cookie.
+///
+/// The UI only has a single channel to talk back to the agent, so the manager owns the receiving
+/// end of it and forwards each [`AuthenticationUserEvent`] to the session it belongs to.
+#[derive(Debug, Clone)]
+pub struct SessionManager {
+    sessions: Arc<Mutex<HashMap<String, mpsc::Sender<AuthenticationUserEvent>>>>,
+    frontend: FrontendProxy,
+    /// How long a session may go without the user doing anything before it is cancelled.
+    idle_timeout: Option<Duration>,
+}
+
+impl SessionManager {
+    pub fn new(
+        receiver: mpsc::Receiver<AuthenticationUserEvent>,
+        frontend: FrontendProxy,
+        idle_timeout: Option<Duration>,
+    ) -> Self {
+        let manager = Self {
+            sessions: Arc::default(),
+            frontend,
+            idle_timeout,
+        };
+        tokio::spawn(manager.clone().route(receiver));
+        manager
+    }
+
+    async fn route(self, mut receiver: mpsc::Receiver<AuthenticationUserEvent>) {
+        while let Some(event) = receiver.recv().await {
+            self.dispatch(event).await;
+        }
+        tracing::debug!("user event channel closed, stopping session routing");
+    }
+
+    /// Forwards an event to the session that owns its cookie, if there is one.
+    pub async fn dispatch(&self, event: AuthenticationUserEvent) {
+        let sender = self.sessions.lock().unwrap().get(event.cookie()).cloned();
+        match sender {
+            Some(sender) => {
+                if sender.send(event).await.is_err() {
+                    tracing::debug!("session was closed before the event could be delivered");
+                }
+            }
+            None => tracing::debug!("dropping event for unknown session {}", event.cookie()),
+        }
+    }
+
+    /// Opens a new session for `cookie`. The session is removed again once it is dropped.
+    pub fn open(&self, cookie: &str) -> Result<Session> {
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.contains_key(cookie) {
+            return Err(PolkitError::Failed(format!(
+                "An authentication session for {cookie} is already running."
+            )));
+        }
+
+        let (sender, receiver) = mpsc::channel(8);
+        sessions.insert(cookie.to_string(), sender);
+        tracing::debug!("opened session {}, {} active", cookie, sessions.len());
+
+        Ok(Session {
+            cookie: cookie.to_string(),
+            receiver,
+            idle: self.idle_timeout.map(|timeout| IdleTimer {
+                timeout,
+                frontend: self.frontend.clone(),
+                deadline: None,
+                warned: false,
+                withdrawn: false,
+            }),
+            manager: self.clone(),
+        })
+    }
+}
+
+/// A single authentication request, receiving only the user events for its own cookie.
+#[derive(Debug)]
+pub struct Session {
+    cookie: String,
+    receiver: mpsc::Receiver<AuthenticationUserEvent>,
+    idle: Option<IdleTimer>,
+    manager: SessionManager,
+}
+
+impl Session {
+    /// Waits for the user's next event. Fails with [`PolkitError::Cancelled`] once the user left
+    /// the request alone for the idle timeout. This is cancel safe.
+    pub async fn recv(&mut self) -> Result<AuthenticationUserEvent> {
+        loop {
+            if let Some(idle) = self.idle.as_mut() {
+                idle.withdraw(&self.cookie).await;
+            }
+
+            let event = tokio::select! {
+                event = self.receiver.recv() => event.ok_or_else(|| {
+                    PolkitError::Failed("Failed to receive data. channel closed".to_string())
+                })?,
+                () = IdleTimer::expired(self.idle.as_mut(), &self.cookie) => {
+                    tracing::info!("nobody answered request {}, cancelling it", self.cookie);
+                    return Err(PolkitError::Cancelled(
+                        "Nobody answered the authentication in time.".to_string(),
+                    ));
+                }
+            };
+
+            if let Some(idle) = self.idle.as_mut() {
+                idle.restart();
+            }
+            if !matches!(event, AuthenticationUserEvent::Active { .. }) {
+                return Ok(event);
+            }
+        }
+    }
+
+    /// Whether polkit already cancelled the request, before the frontend was told about it.
+    pub fn cancelled(&mut self) -> bool {
+        while let Ok(event) = self.receiver.try_recv() {
+            if matches!(event, AuthenticationUserEvent::Canceled { .. }) {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// Cancels a session nobody answers, after warning the frontend about it.
+#[derive(Debug)]
+struct IdleTimer {
+    timeout: Duration,
+    frontend: FrontendProxy,
+    /// Unset until the frontend sends its first event, it may still queue the request until then.
+    /// Also unset if the timeout is too long to fall on any time.
+    deadline: Option<Instant>,
+    /// Whether the frontend was warned about the current deadline.
+    warned: bool,
+    /// Whether the frontend still shows a warning about a deadline that was pushed back.
+    withdrawn: bool,
+}
+
+impl IdleTimer {
+    /// Resolves once the deadline passed, warning the frontend [`IDLE_WARNING`] before. Never
+    /// resolves without a timer or a deadline. This is cancel safe.
+    async fn expired(timer: Option<&mut Self>, cookie: &str) {
+        let Some(timer) = timer else {
+            return std::future::pending().await;
+        };
+        let Some(deadline) = timer.deadline else {
+            return std::future::pending().await;
+        };
+
+        if !timer.warned {
+            let warn_at = deadline.checked_sub(IDLE_WARNING).unwrap_or(deadline);
+            tokio::time::sleep_until(warn_at).await;
+            timer
+                .frontend
+                .send(AuthenticationAgentEvent::IdleWarning {
+                    cookie: cookie.to_string(),
+                    until: Some(deadline.into_std()),
+                })
+                .await;
+            timer.warned = true;
+        }
+        tokio::time::sleep_until(deadline).await;
+    }
+
+    /// Pushes the deadline back, the user is still there.
+    fn restart(&mut self) {
+        self.deadline = Instant::now().checked_add(self.timeout);
+        self.withdrawn |= self.warned;
+        self.warned = false;
+    }
+
+    /// Tells the frontend that the warning it shows no longer applies, if it shows one.
+    async fn withdraw(&mut self, cookie: &str) {
+        if self.withdrawn {
+            self.frontend
+                .send(AuthenticationAgentEvent::IdleWarning {
+                    cookie: cookie.to_string(),
+                    until: None,
+                })
+                .await;
+            self.withdrawn = false;
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let mut sessions = self.manager.sessions.lock().unwrap();
+        sessions.remove(&self.cookie);
+        tracing::debug!("closed session {}, {} active", self.cookie, sessions.len());
+    }
+}
@@ -0,0 +1,537 @@
+//! Answers requests on the controlling terminal, for sessions without a display to show the dialog
+//! on, like SSH logins, a TTY or a crashed compositor.
+//!
+//! It consumes the same [`AuthenticationAgentEvent`]s as the dialog and answers with the same
+//! [`AuthenticationUserEvent`]s. Pressing Ctrl-D at any question cancels the request.
+
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    os::fd::AsRawFd,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Instant,
+};
+
+use eyre::{Result, WrapErr};
+use gettextrs::{gettext, ngettext};
+use tokio::{sync::mpsc, task::JoinHandle};
+use zeroize::Zeroizing;
+
+use crate::{
+    events::{AuthenticationAgentEvent, AuthenticationUserEvent, RequestDetails},
+    frontend::{Frontend, FrontendChannel},
+    future::BoxFuture,
+    identity::{self, UserIdentity},
+};
+
+#[derive(Debug)]
+pub struct TtyFrontend;
+
+impl TtyFrontend {
+    /// Whether to use the terminal instead of the dialog: when asked to with `--tty`, or when there
+    /// is no display to open the dialog on.
+    pub fn requested() -> bool {
+        std::env::args().any(|arg| arg == "--tty")
+            || (std::env::var_os("WAYLAND_DISPLAY").is_none()
+                && std::env::var_os("DISPLAY").is_none())
+    }
+}
+
+impl Frontend for TtyFrontend {
+    fn run(self: Box<Self>, channel: FrontendChannel) -> BoxFuture<'static, Result<()>> {
+        Box::pin(async { tokio::spawn(run(channel)).await? })
+    }
+}
+
+async fn run(channel: FrontendChannel) -> Result<()> {
+    let FrontendChannel {
+        mut events,
+        answers: sender,
+    } = channel;
+    let tty = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .context("Could not open the controlling terminal")?;
+    let mut frontend = Terminal {
+        tty,
+        sender,
+        current: None,
+        pending: VecDeque::new(),
+        question: None,
+        asked: Arc::new(AtomicU64::new(0)),
+        reading: None,
+    };
+
+    loop {
+        let reading = frontend.reading.as_mut();
+        let has_reading = reading.is_some();
+        tokio::select! {
+            event = events.recv() => {
+                let Some(event) = event else {
+                    return Ok(());
+                };
+                tracing::debug!("received event {:#?}", event);
+                frontend.handle(event).await?;
+            }
+            line = async { reading.unwrap().await }, if has_reading => {
+                frontend.reading = None;
+                match line?? {
+                    Some(Some(line)) => frontend.answer(line).await?,
+                    Some(None) => frontend.cancel().await?,
+                    // The question was replaced before it was answered.
+                    None => {}
+                }
+            }
+        }
+    }
+}
+
+/// A request polkit sent, as far as the terminal cares.
+struct Request {
+    cookie: String,
+    message: String,
+    identities: Vec<UserIdentity>,
+    details: RequestDetails,
+    /// The position of the identity PAM was last started for, retrying starts it for them again.
+    selected: usize,
+}
+
+impl Request {
+    /// The positions of the identities that can be picked.
+    fn choices(&self) -> Vec<usize> {
+        (0..self.identities.len())
+            .filter(|&position| self.identities[position].selectable())
+            .collect()
+    }
+}
+
+/// What the line currently being read answers.
+enum Question {
+    Identity,
+    Prompt {
+        step: u32,
+    },
+    NewPassword,
+    ConfirmPassword(Zeroizing<String>),
+    /// PAM gave up without asking anything, Enter starts it over.
+    Retry,
+}
+
+/// A line read from the terminal, `None` on Ctrl-D, or no line at all if the question was replaced.
+type Line = Option<Option<Zeroizing<String>>>;
+
+struct Terminal {
+    tty: File,
+    sender: mpsc::Sender<AuthenticationUserEvent>,
+    current: Option<Request>,
+    pending: VecDeque<Request>,
+    question: Option<Question>,
+    /// Counts the questions asked, a read gives up once it no longer answers the latest one.
+    asked: Arc<AtomicU64>,
+    reading: Option<JoinHandle<io::Result<Line>>>,
+}
+
+impl Terminal {
+    fn say(&self, text: &str) -> Result<()> {
+        writeln!(&self.tty, "{text}")?;
+        Ok(())
+    }
+
+    fn is_current(&self, cookie: &str) -> bool {
+        self.current.as_ref().is_some_and(|r| r.cookie == cookie)
+    }
+
+    /// Prints `prompt` and starts reading the answer to `question`.
+    async fn ask(&mut self, question: Question, prompt: &str, echo: bool) -> Result<()> {
+        self.stop_reading().await?;
+
+        write!(&self.tty, "{prompt} ")?;
+        (&self.tty).flush()?;
+
+        let id = self.asked.fetch_add(1, Ordering::SeqCst) + 1;
+        let tty = self.tty.try_clone()?;
+        let asked = self.asked.clone();
+        self.question = Some(question);
+        self.reading = Some(tokio::task::spawn_blocking(move || {
+            read_line(&tty, echo, id, &asked)
+        }));
+        Ok(())
+    }
+
+    /// Abandons the question being read, once the terminal is back the way it was.
+    async fn stop_reading(&mut self) -> Result<()> {
+        self.question = None;
+        self.asked.fetch_add(1, Ordering::SeqCst);
+        if let Some(reading) = self.reading.take() {
+            let _ = reading.await?;
+        }
+        Ok(())
+    }
+
+    async fn send(&self, event: AuthenticationUserEvent) -> Result<()> {
+        self.sender
+            .send(event)
+            .await
+            .map_err(|_| eyre::eyre!("The agent stopped listening"))
+    }
+
+    async fn next_request(&mut self) -> Result<()> {
+        self.stop_reading().await?;
+        self.current = self.pending.pop_front();
+        let Some(request) = &self.current else {
+            return Ok(());
+        };
+
+        self.say("")?;
+        self.say(&gettext("Authentication Required"))?;
+        self.say(&request.message)?;
+        self.say(&request.details.to_string())?;
+        self.say(&gettext("Press Ctrl-D to cancel."))?;
+        self.send(AuthenticationUserEvent::Active {
+            cookie: request.cookie.clone(),
+        })
+        .await?;
+
+        match request.choices().len() {
+            0 => {
+                self.say(&gettext(
+                    "None of the identities that may authorize this exist on this system.",
+                ))?;
+                // The agent confirms the cancel, which moves on to the next request.
+                let cookie = request.cookie.clone();
+                self.send(AuthenticationUserEvent::Canceled { cookie })
+                    .await
+            }
+            1 => self.select_identity(request.selected).await,
+            _ => self.ask_identity().await,
+        }
+    }
+
+    /// Lists the identities that can be picked, numbered from one.
+    async fn ask_identity(&mut self) -> Result<()> {
+        let Some(request) = &self.current else {
+            return Ok(());
+        };
+        let choices = request.choices();
+        let default = choices
+            .iter()
+            .position(|&position| position == request.selected)
+            .unwrap_or(0);
+        for (number, &position) in choices.iter().enumerate() {
+            let label = request.identities[position].label();
+            self.say(&format!("  {}) {}", number + 1, label))?;
+        }
+        let prompt = gettext("Authenticate as [{}]:").replace("{}", &(default + 1).to_string());
+        self.ask(Question::Identity, &prompt, true).await
+    }
+
+    async fn select_identity(&mut self, position: usize) -> Result<()> {
+        let Some(request) = self.current.as_mut() else {
+            return Ok(());
+        };
+        let Some(username) = request
+            .identities
+            .get(position)
+            .and_then(|identity| identity.username.clone())
+        else {
+            return Ok(());
+        };
+        request.selected = position;
+        let cookie = request.cookie.clone();
+        self.send(AuthenticationUserEvent::SelectedIdentity { cookie, username })
+            .await
+    }
+
+    async fn cancel(&mut self) -> Result<()> {
+        self.say("")?;
+        let Some(request) = &self.current else {
+            return Ok(());
+        };
+        self.send(AuthenticationUserEvent::Canceled {
+            cookie: request.cookie.clone(),
+        })
+        .await?;
+        self.say(&gettext("Request cancelled."))?;
+        self.next_request().await
+    }
+
+    async fn answer(&mut self, line: Zeroizing<String>) -> Result<()> {
+        let (Some(request), Some(question)) = (&self.current, self.question.take()) else {
+            return Ok(());
+        };
+        let cookie = request.cookie.clone();
+
+        match question {
+            Question::Identity => {
+                let choices = request.choices();
+                let position = match line.trim() {
+                    "" => Some(request.selected),
+                    answer => answer
+                        .parse::<usize>()
+                        .ok()
+                        .and_then(|n| n.checked_sub(1))
+                        .and_then(|n| choices.get(n).copied()),
+                };
+                match position {
+                    Some(position) => self.select_identity(position).await?,
+                    None => self.ask_identity().await?,
+                }
+            }
+            Question::Prompt { step } => {
+                self.send(AuthenticationUserEvent::ProvidedResponse {
+                    cookie,
+                    step,
+                    response: line.to_string(),
+                })
+                .await?;
+            }
+            Question::NewPassword => {
+                if line.is_empty() {
+                    self.say(&gettext("The new password can't be empty."))?;
+                    self.ask(Question::NewPassword, &gettext("New password:"), false)
+                        .await?;
+                } else {
+                    self.ask(
+                        Question::ConfirmPassword(line),
+                        &gettext("Confirm new password:"),
+                        false,
+                    )
+                    .await?;
+                }
+            }
+            Question::ConfirmPassword(password) => {
+                if *password != *line {
+                    self.say(&gettext("The passwords don't match."))?;
+                    self.ask(Question::NewPassword, &gettext("New password:"), false)
+                        .await?;
+                } else {
+                    self.send(AuthenticationUserEvent::ProvidedNewPassword {
+                        cookie,
+                        password: password.to_string(),
+                    })
+                    .await?;
+                }
+            }
+            Question::Retry => {
+                if let Some(position) = self.current.as_ref().map(|r| r.selected) {
+                    self.select_identity(position).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle(&mut self, event: AuthenticationAgentEvent) -> Result<()> {
+        match &event {
+            AuthenticationAgentEvent::Started {
+                cookie,
+                message,
+                identities,
+                details,
+            } => {
+                self.pending.push_back(Request {
+                    cookie: cookie.clone(),
+                    message: message.clone(),
+                    identities: identities.clone(),
+                    details: details.as_ref().clone(),
+                    selected: identity::default_index(identities),
+                });
+                if self.current.is_none() {
+                    self.next_request().await?;
+                }
+            }
+            AuthenticationAgentEvent::Canceled { cookie } => {
+                if self.is_current(cookie) {
+                    self.say("")?;
+                    self.say(&gettext("Request cancelled."))?;
+                    self.next_request().await?;
+                } else {
+                    self.pending.retain(|r| &r.cookie != cookie);
+                }
+            }
+            AuthenticationAgentEvent::AuthorizationSucceeded { cookie } => {
+                if self.is_current(cookie) {
+                    self.say(&gettext("Authorized."))?;
+                    self.next_request().await?;
+                }
+            }
+            AuthenticationAgentEvent::AttemptsExhausted { cookie } => {
+                if self.is_current(cookie) {
+                    self.stop_reading().await?;
+                    self.say(&gettext(
+                        "Too many failed attempts, the request was cancelled.",
+                    ))?;
+                }
+            }
+            AuthenticationAgentEvent::AuthorizationRetry {
+                cookie,
+                reason,
+                messages,
+            } => {
+                if self.is_current(cookie) {
+                    self.stop_reading().await?;
+                    self.say(&reason.message())?;
+                    for message in messages {
+                        self.say(&format!("  {message}"))?;
+                    }
+                    // If PAM starts over on its own, its first prompt replaces this question.
+                    self.ask(Question::Retry, &gettext("Press Enter to try again."), true)
+                        .await?;
+                }
+            }
+            AuthenticationAgentEvent::Prompt {
+                cookie,
+                step,
+                prompt,
+                echo,
+            } => {
+                if self.is_current(cookie) {
+                    self.ask(Question::Prompt { step: *step }, prompt.trim_end(), *echo)
+                        .await?;
+                }
+            }
+            AuthenticationAgentEvent::PasswordChange { cookie, error } => {
+                if self.is_current(cookie) {
+                    self.stop_reading().await?;
+                    match error {
+                        Some(error) => self.say(error)?,
+                        None => self.say(&gettext(
+                            "Your password has expired. Choose a new one to continue.",
+                        ))?,
+                    }
+                    self.ask(Question::NewPassword, &gettext("New password:"), false)
+                        .await?;
+                }
+            }
+            AuthenticationAgentEvent::LockedOut { cookie, until } => {
+                if self.is_current(cookie) {
+                    let text = match until {
+                        Some(until) => {
+                            let minutes = until
+                                .saturating_duration_since(Instant::now())
+                                .as_secs()
+                                .div_ceil(60);
+                            ngettext(
+                                "Account locked, try again in {} minute",
+                                "Account locked, try again in {} minutes",
+                                minutes as u32,
+                            )
+                            .replace("{}", &minutes.to_string())
+                        }
+                        None => gettext("This account is locked."),
+                    };
+                    self.say(&text)?;
+                }
+            }
+            AuthenticationAgentEvent::IdleWarning {
+                cookie,
+                until: Some(until),
+            } => {
+                if self.is_current(cookie) {
+                    let seconds = until.saturating_duration_since(Instant::now()).as_secs();
+                    self.say(&format!(
+                        "\n{}",
+                        ngettext(
+                            "The request is cancelled in {} second unless you answer.",
+                            "The request is cancelled in {} seconds unless you answer.",
+                            seconds as u32,
+                        )
+                        .replace("{}", &seconds.to_string())
+                    ))?;
+                }
+            }
+            AuthenticationAgentEvent::IdleWarning { until: None, .. } => {}
+            AuthenticationAgentEvent::Info { cookie, message }
+            | AuthenticationAgentEvent::Error { cookie, message } => {
+                if self.is_current(cookie) {
+                    // Starts on a new line, the helper may talk while a question is open.
+                    self.say(&format!("\n{message}"))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads one line from the terminal, with echo turned off for secrets. Gives up and returns `None`
+/// once `asked` moved past `id`, and returns `Some(None)` on Ctrl-D.
+fn read_line(tty: &File, echo: bool, id: u64, asked: &AtomicU64) -> io::Result<Line> {
+    let fd = tty.as_raw_fd();
+    let _echo = (!echo).then(|| EchoOff::new(fd)).transpose()?;
+
+    let mut line = Zeroizing::new(Vec::new());
+    let mut buffer = Zeroizing::new([0u8; 256]);
+    loop {
+        if asked.load(Ordering::SeqCst) != id {
+            return Ok(None);
+        }
+
+        let mut poll = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: `poll` points at a single valid pollfd.
+        let ready = unsafe { libc::poll(&mut poll, 1, 250) };
+        if ready < 0 {
+            let error = io::Error::last_os_error();
+            if error.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(error);
+        }
+        if ready == 0 {
+            continue;
+        }
+
+        // SAFETY: `buffer` is valid for writes of its whole length.
+        let read = unsafe { libc::read(fd, buffer.as_mut_ptr().cast(), buffer.len()) };
+        if read < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if read == 0 {
+            return Ok(Some(None));
+        }
+        line.extend_from_slice(&buffer[..read as usize]);
+        if line.ends_with(b"\n") {
+            line.pop();
+            let text = String::from_utf8_lossy(&line).into_owned();
+            return Ok(Some(Some(Zeroizing::new(text))));
+        }
+    }
+}
+
+/// Turns off echo on the terminal until dropped. Newlines are still echoed, so the cursor moves on.
+struct EchoOff {
+    fd: i32,
+    original: libc::termios,
+}
+
+impl EchoOff {
+    fn new(fd: i32) -> io::Result<Self> {
+        // SAFETY: termios is plain data that tcgetattr fills in.
+        let mut original: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(fd, &mut original) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut silent = original;
+        silent.c_lflag &= !libc::ECHO;
+        silent.c_lflag |= libc::ECHONL;
+        if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &silent) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { fd, original })
+    }
+}
+
+impl Drop for EchoOff {
+    fn drop(&mut self) {
+        unsafe { libc::tcsetattr(self.fd, libc::TCSANOW, &self.original) };
+    }
+}
@@ -1,40 +1,290 @@
-use gettextrs::gettext;
+use eyre::{Result, WrapErr};
+use gettextrs::{gettext, ngettext};
 use gtk::prelude::*;
 use gtk4::{
+    gio,
     glib::{clone, spawn_future_local},
     prelude::OrientableExt,
 };
-use relm4::prelude::*;
+use relm4::{RelmApp, prelude::*};
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::{Duration, Instant},
+};
 use tokio::sync::mpsc;
 
-use crate::events::{AuthenticationAgentEvent, AuthenticationUserEvent};
+use crate::{
+    events::{AuthenticationAgentEvent, AuthenticationUserEvent, RequestDetails},
+    frontend::{Frontend, FrontendChannel},
+    future::BoxFuture,
+    identity::{self, UserIdentity},
+};
 
 #[derive(Debug, zeroize::ZeroizeOnDrop)]
 pub enum AppMsg {
-    Confirm { user: String, password: String },
+    /// The answer to the prompt that is currently shown.
+    Confirm {
+        response: String,
+    },
+    /// The new password and its confirmation, while the expired password is being changed.
+    ChangePassword {
+        password: String,
+        confirm: String,
+    },
     Cancel,
+    /// The user typed something, so they are still there.
+    Activity,
+    /// The user picked the identity at this position in the dropdown.
+    SelectIdentity(u32),
     AuthEvent(AuthenticationAgentEvent),
 }
 
+#[derive(Debug)]
+pub enum AppCommand {
+    /// A second passed while a countdown is shown.
+    Tick,
+    /// Time to close a request that ran out of attempts, once its message was shown.
+    Dismiss(String),
+    /// The request was cancelled without the cancel button, which clears the entries otherwise.
+    ClearEntries,
+}
+
+/// How long the dialog stays open to say that a request ran out of attempts.
+const DISMISS_DELAY: Duration = Duration::from_secs(3);
+
+/// A request that arrived while another one was being shown.
+#[derive(Debug)]
+struct PendingRequest {
+    cookie: String,
+    message: String,
+    identities: Vec<UserIdentity>,
+    details: RequestDetails,
+}
+
 pub struct App {
     message: String,
-    identities: Vec<String>,
+    identities: Vec<UserIdentity>,
+    /// The position of the selected identity, kept here since the dropdown's model is rebuilt.
+    selected: usize,
+    /// Set when another request is shown, the dropdown's model is only rebuilt then.
+    identities_changed: bool,
+    /// The identities as seen by the dropdown's row factory, which can't borrow the model.
+    identity_rows: Rc<RefCell<Vec<UserIdentity>>>,
+    details: RequestDetails,
+    icon: gio::Icon,
     cookie: Option<String>,
     retry_message: Option<String>,
+    /// What PAM said during the last failed attempt, shown below the retry message.
+    failure_messages: Vec<String>,
     authenticating: bool,
+    /// Whether the helper is waiting on something other than the user typing, like a fingerprint.
+    waiting: bool,
+    /// The step of the prompt the helper is currently waiting on, which the answer has to repeat.
+    step: Option<u32>,
+    /// The label of that prompt, as PAM worded it.
+    prompt: Option<String>,
+    prompt_echo: bool,
+    /// Whether PAM asked for a new password because the current one expired.
+    changing_password: bool,
+    /// Why the last new password was not accepted, by us or by PAM.
+    change_error: Option<String>,
+    /// When the account unlocks again, if PAM reported a timed lockout.
+    locked_until: Option<Instant>,
+    /// When the agent cancels the shown request for being left alone, once it warned about it.
+    idle_until: Option<Instant>,
+    /// Whether a [`AppCommand::Tick`] is already scheduled.
+    ticking: bool,
+    /// Set when the request ran out of attempts and the dialog is about to close.
+    closing: bool,
+    pending: VecDeque<PendingRequest>,
     sender: mpsc::Sender<AuthenticationUserEvent>, // chosen_identity: Option<String>,
 }
 
+impl App {
+    /// Clears the current request and shows the next queued one, if any.
+    ///
+    /// The agent only starts the helper for a request once it is shown, so a fingerprint reader
+    /// can't be claimed by a request that is still waiting in the queue.
+    async fn next_request(&mut self) {
+        self.retry_message = None;
+        self.failure_messages.clear();
+        self.authenticating = false;
+        self.step = None;
+        self.prompt = None;
+        self.prompt_echo = false;
+        self.changing_password = false;
+        self.change_error = None;
+        self.locked_until = None;
+        self.idle_until = None;
+        self.waiting = false;
+        self.closing = false;
+
+        if let Some(request) = self.pending.pop_front() {
+            tracing::debug!("showing queued request {}", request.cookie);
+            self.cookie = Some(request.cookie);
+            self.message = request.message;
+            self.identities = request.identities;
+            self.details = request.details;
+            self.icon = request_icon(&self.details);
+            self.selected = identity::default_index(&self.identities);
+            if !self.identities.iter().any(UserIdentity::selectable) {
+                self.retry_message = Some(gettext(
+                    "None of the identities that may authorize this exist on this system.",
+                ));
+            }
+            self.active().await;
+            self.select_identity().await;
+        } else {
+            self.cookie = None;
+            self.message.clear();
+            self.identities.clear();
+            self.details = RequestDetails::default();
+            self.selected = 0;
+        }
+        *self.identity_rows.borrow_mut() = self.identities.clone();
+        self.identities_changed = true;
+    }
+
+    /// Tells the agent which identity is selected, so it can (re)start the helper for it.
+    async fn select_identity(&self) {
+        let username = self
+            .identities
+            .get(self.selected)
+            .and_then(|identity| identity.username.clone());
+        let (Some(cookie), Some(username)) = (&self.cookie, username) else {
+            return;
+        };
+        self.sender
+            .send(AuthenticationUserEvent::SelectedIdentity {
+                cookie: cookie.clone(),
+                username,
+            })
+            .await
+            .unwrap();
+    }
+
+    /// Tells the agent that the user is still there, which keeps the request from timing out.
+    async fn active(&self) {
+        let Some(cookie) = &self.cookie else {
+            return;
+        };
+        self.sender
+            .send(AuthenticationUserEvent::Active {
+                cookie: cookie.clone(),
+            })
+            .await
+            .unwrap();
+    }
+
+    /// One item per identity, in the same order. The rows themselves are drawn by the factory.
+    fn identity_list(&self) -> gtk::StringList {
+        let labels: Vec<String> = self.identities.iter().map(UserIdentity::label).collect();
+        gtk::StringList::new(&labels.iter().map(String::as_str).collect::<Vec<_>>())
+    }
+
+    /// Refreshes the countdowns in a second while any is running, unless a refresh is already on
+    /// its way.
+    fn schedule_tick(&mut self, sender: &AsyncComponentSender<Self>) {
+        if self.ticking || (self.locked_until.is_none() && self.idle_until.is_none()) {
+            return;
+        }
+        self.ticking = true;
+        sender.oneshot_command(async {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            AppCommand::Tick
+        });
+    }
+
+    /// The seconds left before the request is cancelled, once the agent warned about it.
+    fn idle_warning(&self) -> Option<u64> {
+        let until = self.idle_until.filter(|_| !self.closing)?;
+        Some(until.saturating_duration_since(Instant::now()).as_secs())
+    }
+
+    /// The countdown shown while the account is locked, like "Account locked, try again in 4:59".
+    fn lockout_text(&self) -> String {
+        let Some(until) = self.locked_until else {
+            return String::new();
+        };
+        let remaining = until.saturating_duration_since(Instant::now()).as_secs();
+        gettext("Account locked, try again in {}")
+            .replace("{}", &format!("{}:{:02}", remaining / 60, remaining % 60))
+    }
+}
+
+const FALLBACK_ICON: &str = "dialog-password";
+
+/// Prefers the action's own icon, then the icon of the application that asked for authorization.
+fn request_icon(details: &RequestDetails) -> gio::Icon {
+    if let Some(icon_name) = &details.icon_name {
+        return gio::ThemedIcon::new(icon_name).upcast();
+    }
+
+    details
+        .executable
+        .as_deref()
+        .and_then(application_icon)
+        .unwrap_or_else(|| gio::ThemedIcon::new(FALLBACK_ICON).upcast())
+}
+
+/// Finds the icon of the installed application whose .desktop file runs `executable`.
+fn application_icon(executable: &str) -> Option<gio::Icon> {
+    let name = Path::new(executable).file_name()?;
+    gio::AppInfo::all()
+        .into_iter()
+        .find(|app| app.executable().file_name() == Some(name))
+        .and_then(|app| app.icon())
+}
+
+/// Builds the dropdown rows, showing each identity's avatar and full name. Identities that could not
+/// be resolved are greyed out and can't be picked.
+fn identity_factory(rows: Rc<RefCell<Vec<UserIdentity>>>) -> gtk::SignalListItemFactory {
+    let factory = gtk::SignalListItemFactory::new();
+    factory.connect_setup(|_, item| {
+        let item = item.downcast_ref::<gtk::ListItem>().unwrap();
+        let row = gtk::Box::new(gtk::Orientation::Horizontal, 8);
+        let avatar = gtk::Image::new();
+        avatar.set_pixel_size(24);
+        let label = gtk::Label::new(None);
+        label.set_xalign(0.0);
+        row.append(&avatar);
+        row.append(&label);
+        item.set_child(Some(&row));
+    });
+    factory.connect_bind(move |_, item| {
+        let item = item.downcast_ref::<gtk::ListItem>().unwrap();
+        let Some(row) = item.child() else {
+            return;
+        };
+        let avatar: gtk::Image = row.first_child().and_downcast().unwrap();
+        let label: gtk::Label = row.last_child().and_downcast().unwrap();
+
+        let rows = rows.borrow();
+        let Some(identity) = rows.get(item.position() as usize) else {
+            return;
+        };
+        match &identity.avatar {
+            Some(path) => avatar.set_from_file(Some(path)),
+            None => avatar.set_icon_name(Some("avatar-default-symbolic")),
+        }
+        label.set_label(&identity.label());
+        row.set_sensitive(identity.selectable());
+        item.set_selectable(identity.selectable());
+        item.set_activatable(identity.selectable());
+    });
+    factory
+}
+
 #[allow(unused_assignments)]
 #[relm4::component(async, pub)]
 impl AsyncComponent for App {
     type Input = AppMsg;
     type Output = ();
-    type Init = (
-        mpsc::Sender<AuthenticationUserEvent>,
-        mpsc::Receiver<AuthenticationAgentEvent>,
-    );
-    type CommandOutput = ();
+    type Init = FrontendChannel;
+    type CommandOutput = AppCommand;
 
     view! {
         gtk::Window {
@@ -60,6 +310,13 @@ impl AsyncComponent for App {
                 set_margin_start: 56,
                 set_orientation: gtk::Orientation::Vertical,
 
+                gtk::Image {
+                    set_pixel_size: 48,
+                    set_margin_top: 16,
+                    #[watch]
+                    set_from_gicon: &model.icon,
+                },
+
                 gtk::Label {
                     set_markup: &format!(r#"<b><span size='x-large'>{}</span></b>"#, gettext("Authentication Required")),
                     set_margin_horizontal: 16,
@@ -78,6 +335,14 @@ impl AsyncComponent for App {
                     set_margin_bottom: if model.retry_message.is_some() { 8 } else { 16 },
                 },
 
+                gtk::Spinner {
+                    #[watch]
+                    set_spinning: model.waiting,
+                    #[watch]
+                    set_visible: model.waiting,
+                    set_margin_bottom: 8,
+                },
+
                 gtk::Label {
                     #[watch]
                     set_label: &if let Some(retry_message) = model.retry_message.clone() {
@@ -92,6 +357,53 @@ impl AsyncComponent for App {
                     set_halign: gtk::Align::Center,
                 },
 
+                gtk::Label {
+                    #[watch]
+                    set_label: &model.failure_messages.join("\n"),
+                    #[watch]
+                    set_visible: !model.failure_messages.is_empty(),
+                    set_margin_bottom: 16,
+                    set_halign: gtk::Align::Center,
+                    set_justify: gtk::Justification::Center,
+                    set_wrap: true,
+                    add_css_class: "dim-label",
+                },
+
+                gtk::Label {
+                    #[watch]
+                    set_label: &model.idle_warning().map(|remaining| ngettext(
+                        "Cancelling in {} second",
+                        "Cancelling in {} seconds",
+                        remaining as u32,
+                    ).replace("{}", &remaining.to_string())).unwrap_or_default(),
+                    #[watch]
+                    set_visible: model.idle_warning().is_some(),
+                    set_margin_bottom: 8,
+                    set_halign: gtk::Align::Center,
+                },
+
+                gtk::Label {
+                    #[watch]
+                    set_label: &model.lockout_text(),
+                    #[watch]
+                    set_visible: model.locked_until.is_some(),
+                    set_margin_bottom: 16,
+                    set_halign: gtk::Align::Center,
+                },
+
+                gtk::Label {
+                    #[watch]
+                    set_label: &ngettext(
+                        "{} more request waiting",
+                        "{} more requests waiting",
+                        model.pending.len() as u32,
+                    ).replace("{}", &model.pending.len().to_string()),
+                    #[watch]
+                    set_visible: !model.pending.is_empty(),
+                    set_margin_bottom: 8,
+                    set_halign: gtk::Align::Center,
+                },
+
                 gtk::Box {
                     set_baseline_position: gtk::BaselinePosition::Center,
                     set_spacing: 18,
@@ -101,30 +413,130 @@ impl AsyncComponent for App {
                         set_margin_bottom: 8,
                         set_hexpand: true,
                         #[watch]
-                        set_model: Some( &gtk::StringList::new(&model.identities.iter().map(AsRef::as_ref).collect::<Vec<_>>()) ),
+                        set_sensitive: !model.authenticating && !model.closing,
+                        set_factory: Some(&identity_factory(model.identity_rows.clone())),
+                        #[track(model.identities_changed)]
+                        #[block_signal(selected_handler)]
+                        set_model: Some( &model.identity_list() ),
                         #[watch]
-                        set_selected: {
-                            if let Some(Ok(user))
-                            = etc_passwd::Passwd::current_user().ok().flatten().map(|p| p.name.into_string()) {
-                                model.identities.iter().position(|n| n == &user).unwrap_or(0) as u32
-                            } else { 0 }
-
-                        }
+                        #[block_signal(selected_handler)]
+                        set_selected: model.selected as u32,
 
+                        connect_selected_notify[sender] => move |dropdown| {
+                            sender.input(AppMsg::SelectIdentity(dropdown.selected()));
+                        } @selected_handler,
                     }
                 },
 
                 #[name = "password_entry"]
                 gtk::PasswordEntry {
                     set_hexpand: true,
-                    set_placeholder_text: Some( &gettext("Password") ),
+                    #[watch]
+                    set_placeholder_text: Some( &model.prompt.clone().unwrap_or_else(|| gettext("Password")) ),
                     set_show_peek_icon: true,
                     #[watch]
-                    set_editable: !model.authenticating,
+                    set_editable: model.step.is_some() && model.locked_until.is_none(),
+                    #[watch]
+                    set_visible: !model.prompt_echo && !model.changing_password,
 
                     connect_activate[confirm_button] => move |_| {
                         confirm_button.emit_clicked();
-                    }
+                    },
+                    connect_changed[sender] => move |_| {
+                        sender.input(AppMsg::Activity);
+                    },
+                },
+
+                #[name = "response_entry"]
+                gtk::Entry {
+                    set_hexpand: true,
+                    #[watch]
+                    set_placeholder_text: model.prompt.as_deref(),
+                    #[watch]
+                    set_editable: model.step.is_some(),
+                    #[watch]
+                    set_visible: model.prompt_echo,
+
+                    connect_activate[confirm_button] => move |_| {
+                        confirm_button.emit_clicked();
+                    },
+                    connect_changed[sender] => move |_| {
+                        sender.input(AppMsg::Activity);
+                    },
+                },
+
+                #[name = "change_box"]
+                gtk::Box {
+                    set_orientation: gtk::Orientation::Vertical,
+                    set_spacing: 8,
+                    #[watch]
+                    set_visible: model.changing_password,
+
+                    gtk::Label {
+                        set_label: &gettext("Your password has expired. Choose a new one to continue."),
+                        set_wrap: true,
+                        set_halign: gtk::Align::Center,
+                    },
+
+                    #[name = "new_password_entry"]
+                    gtk::PasswordEntry {
+                        set_hexpand: true,
+                        set_placeholder_text: Some( &gettext("New password") ),
+                        set_show_peek_icon: true,
+                        #[watch]
+                        set_editable: !model.authenticating,
+
+                        connect_activate[confirm_password_entry] => move |_| {
+                            confirm_password_entry.grab_focus();
+                        },
+                        connect_changed[sender] => move |_| {
+                            sender.input(AppMsg::Activity);
+                        },
+                    },
+
+                    #[name = "confirm_password_entry"]
+                    gtk::PasswordEntry {
+                        set_hexpand: true,
+                        set_placeholder_text: Some( &gettext("Confirm new password") ),
+                        set_show_peek_icon: true,
+                        #[watch]
+                        set_editable: !model.authenticating,
+
+                        connect_activate[confirm_button] => move |_| {
+                            confirm_button.emit_clicked();
+                        },
+                        connect_changed[sender] => move |_| {
+                            sender.input(AppMsg::Activity);
+                        },
+                    },
+
+                    gtk::Label {
+                        #[watch]
+                        set_label: model.change_error.as_deref().unwrap_or_default(),
+                        #[watch]
+                        set_visible: model.change_error.is_some(),
+                        set_wrap: true,
+                        set_halign: gtk::Align::Start,
+                        set_xalign: 0.0,
+                        add_css_class: "error",
+                    },
+                },
+
+                gtk::Expander {
+                    set_label: Some( &gettext("Details") ),
+                    set_margin_top: 8,
+
+                    #[wrap(Some)]
+                    set_child = &gtk::Label {
+                        #[watch]
+                        set_label: &model.details.to_string(),
+                        set_margin_top: 4,
+                        set_halign: gtk::Align::Start,
+                        set_xalign: 0.0,
+                        set_selectable: true,
+                        set_wrap: true,
+                        set_wrap_mode: gtk::pango::WrapMode::WordChar,
+                    },
                 },
 
                 gtk::FlowBox {
@@ -138,20 +550,41 @@ impl AsyncComponent for App {
 
                     #[name = "cancel_button"]
                     append = &gtk::Button::with_label(&gettext("Cancel")){
-                        connect_clicked[sender, password_entry] => move |_| {
+                        connect_clicked[sender, password_entry, response_entry, new_password_entry, confirm_password_entry] => move |_| {
 
                             sender.input(AppMsg::Cancel);
                             password_entry.set_text("");
+                            response_entry.set_text("");
+                            new_password_entry.set_text("");
+                            confirm_password_entry.set_text("");
                         }
                     },
 
                     #[name = "confirm_button"]
                     append = &gtk::Button::with_label(&gettext("Confirm")) {
-                        connect_clicked[sender, identity_dropdown, password_entry] => move |_| {
-                            let user: gtk::StringObject = identity_dropdown.selected_item().unwrap().dynamic_cast().unwrap();
+                        #[watch]
+                        set_sensitive: model.locked_until.is_none() && !model.authenticating && !model.closing,
 
-                            sender.input(AppMsg::Confirm { user: user.string().to_string(), password: password_entry.text().to_string()});
+                        connect_clicked[sender, password_entry, response_entry, change_box, new_password_entry, confirm_password_entry] => move |_| {
+                            if change_box.is_visible() {
+                                sender.input(AppMsg::ChangePassword {
+                                    password: new_password_entry.text().to_string(),
+                                    confirm: confirm_password_entry.text().to_string(),
+                                });
+                                new_password_entry.set_text("");
+                                confirm_password_entry.set_text("");
+                                return;
+                            }
+
+                            let response = if response_entry.is_visible() {
+                                response_entry.text()
+                            } else {
+                                password_entry.text()
+                            };
+
+                            sender.input(AppMsg::Confirm { response: response.to_string() });
                             password_entry.set_text("");
+                            response_entry.set_text("");
 
                         }
                     }
@@ -166,20 +599,38 @@ impl AsyncComponent for App {
         root: Self::Root,
         sender: AsyncComponentSender<Self>,
     ) -> AsyncComponentParts<Self> {
+        let channel = init;
         let model = App {
             message: String::from(""),
             identities: Vec::new(),
-            sender: init.0,
+            selected: 0,
+            identities_changed: false,
+            identity_rows: Rc::default(),
+            details: RequestDetails::default(),
+            icon: gio::ThemedIcon::new(FALLBACK_ICON).upcast(),
+            sender: channel.answers,
             cookie: None,
             authenticating: false,
+            waiting: false,
             retry_message: None,
+            failure_messages: Vec::new(),
+            step: None,
+            prompt: None,
+            prompt_echo: false,
+            changing_password: false,
+            change_error: None,
+            locked_until: None,
+            idle_until: None,
+            ticking: false,
+            closing: false,
+            pending: VecDeque::new(),
         };
 
         spawn_future_local(clone!(
             #[strong]
             sender,
             async move {
-                let mut receiver = init.1;
+                let mut receiver = channel.events;
                 loop {
                     let event = receiver.recv().await.expect("Somehow the channel closed");
                     tracing::debug!("recieved event {:#?}", event);
@@ -197,86 +648,298 @@ impl AsyncComponent for App {
     async fn update(
         &mut self,
         message: Self::Input,
-        _sender: AsyncComponentSender<Self>,
+        sender: AsyncComponentSender<Self>,
         _root: &Self::Root,
     ) {
+        self.identities_changed = false;
         match &message {
-            AppMsg::Confirm { user, password } => {
-                if let Some(cookie) = self.cookie.clone() {
-                    self.sender
-                        .send(AuthenticationUserEvent::ProvidedPassword {
-                            cookie,
-                            username: user.clone(),
-                            password: password.clone(),
-                        })
-                        .await
-                        .unwrap();
-                    self.retry_message = Some(gettext("Authenticating..."));
-                    self.authenticating = true;
+            AppMsg::Confirm { response } => {
+                let Some(cookie) = self.cookie.clone() else {
+                    return;
+                };
+                let Some(step) = self.step.take() else {
+                    // PAM gave up without asking anything, like a fingerprint reader timing out.
+                    // Confirming starts it over.
+                    self.retry_message = None;
+                    self.failure_messages.clear();
+                    self.select_identity().await;
+                    return;
+                };
+                self.prompt = None;
+                self.prompt_echo = false;
+                self.sender
+                    .send(AuthenticationUserEvent::ProvidedResponse {
+                        cookie,
+                        step,
+                        response: response.clone(),
+                    })
+                    .await
+                    .unwrap();
+                self.retry_message = Some(gettext("Authenticating..."));
+                self.failure_messages.clear();
+                self.authenticating = true;
+            }
+            AppMsg::ChangePassword { password, confirm } => {
+                let Some(cookie) = self.cookie.clone() else {
+                    return;
+                };
+                if password.is_empty() {
+                    self.change_error = Some(gettext("The new password can't be empty."));
+                    return;
+                }
+                if password != confirm {
+                    self.change_error = Some(gettext("The passwords don't match."));
+                    return;
+                }
+
+                self.sender
+                    .send(AuthenticationUserEvent::ProvidedNewPassword {
+                        cookie,
+                        password: password.clone(),
+                    })
+                    .await
+                    .unwrap();
+                self.change_error = None;
+                self.retry_message = Some(gettext("Changing password..."));
+                self.authenticating = true;
+            }
+            AppMsg::SelectIdentity(position) => {
+                let position = *position as usize;
+                let selectable = self
+                    .identities
+                    .get(position)
+                    .is_some_and(UserIdentity::selectable);
+                if position != self.selected && selectable {
+                    self.selected = position;
+                    self.retry_message = None;
+                    self.failure_messages.clear();
+                    self.waiting = false;
+                    self.select_identity().await;
                 }
             }
+            AppMsg::Activity => self.active().await,
             AppMsg::Cancel => {
                 if let Some(cookie) = self.cookie.clone() {
                     self.sender
                         .send(AuthenticationUserEvent::Canceled { cookie })
                         .await
                         .unwrap();
-                    self.cookie = None;
-                    self.message = String::new();
-                    self.retry_message = Some(String::new());
-                    self.authenticating = false;
-                    self.identities = Vec::new();
+                    self.next_request().await;
                 }
             }
             AppMsg::AuthEvent(ev) => match ev {
                 AuthenticationAgentEvent::Started {
                     cookie,
                     message,
-                    names,
+                    identities,
+                    details,
                 } => {
+                    self.pending.push_back(PendingRequest {
+                        cookie: cookie.clone(),
+                        message: message.clone(),
+                        identities: identities.clone(),
+                        details: details.as_ref().clone(),
+                    });
                     if self.cookie.is_none() {
-                        self.cookie = Some(cookie.clone());
-                        self.message = message.clone();
-                        self.identities = names.clone();
-                        self.authenticating = false;
-                        self.retry_message = None;
+                        self.next_request().await;
                     }
                 }
                 AuthenticationAgentEvent::Canceled { cookie } => {
-                    if let Some(c) = &self.cookie {
-                        if c == cookie {
-                            self.cookie = None;
-                            self.message = String::new();
-                            self.identities = Vec::new();
-                            self.retry_message = None;
-                            self.authenticating = false;
-                        }
+                    if self.cookie.as_ref() != Some(cookie) {
+                        self.pending.retain(|r| &r.cookie != cookie);
+                    } else if !self.closing {
+                        // A request that ran out of attempts closes on its own, once its message
+                        // could be read.
+                        self.next_request().await;
+                        sender.oneshot_command(async { AppCommand::ClearEntries });
                     }
                 }
                 AuthenticationAgentEvent::AuthorizationSucceeded { cookie } => {
                     if let Some(c) = &self.cookie {
                         if c == cookie {
                             tracing::debug!("Authentication succeeded, closing window.");
-                            self.cookie = None;
-                            self.message.clear();
-                            self.identities.clear();
-                            self.retry_message = None;
-                            self.authenticating = false;
+                            self.next_request().await;
                         }
                     }
                 }
+                AuthenticationAgentEvent::AttemptsExhausted { cookie } => {
+                    if self.cookie.as_ref() == Some(cookie) {
+                        self.retry_message = Some(gettext(
+                            "Too many failed attempts, the request was cancelled.",
+                        ));
+                        self.closing = true;
+                        self.authenticating = false;
+                        self.waiting = false;
+                        self.step = None;
+                        self.prompt = None;
+                        self.prompt_echo = false;
+                        let cookie = cookie.clone();
+                        sender.oneshot_command(async move {
+                            tokio::time::sleep(DISMISS_DELAY).await;
+                            AppCommand::Dismiss(cookie)
+                        });
+                    }
+                }
                 AuthenticationAgentEvent::AuthorizationRetry {
                     cookie,
-                    retry_message,
+                    reason,
+                    messages,
                 } => {
                     if let Some(c) = &self.cookie {
                         if c == cookie {
-                            self.retry_message = retry_message.clone();
+                            self.retry_message = Some(reason.message());
+                            self.failure_messages = messages.clone();
                             self.authenticating = false;
+                            self.step = None;
+                            self.prompt = None;
+                            self.prompt_echo = false;
+                            self.changing_password = false;
+                            self.change_error = None;
+                            self.waiting = false;
+                        }
+                    }
+                }
+                AuthenticationAgentEvent::Prompt {
+                    cookie,
+                    step,
+                    prompt,
+                    echo,
+                } => {
+                    if self.cookie.as_ref() == Some(cookie) {
+                        // Still saying "Authenticating..." about the last answer, while a retry's
+                        // reason stays up for the next attempt.
+                        if self.authenticating {
+                            self.retry_message = None;
+                        }
+                        self.step = Some(*step);
+                        self.prompt = Some(prompt.clone());
+                        self.prompt_echo = *echo;
+                        self.authenticating = false;
+                        self.waiting = false;
+                    }
+                }
+                AuthenticationAgentEvent::PasswordChange { cookie, error } => {
+                    if self.cookie.as_ref() == Some(cookie) {
+                        self.changing_password = true;
+                        self.change_error = error.clone();
+                        self.step = None;
+                        self.prompt = None;
+                        self.prompt_echo = false;
+                        self.retry_message = None;
+                        self.authenticating = false;
+                        self.waiting = false;
+                    }
+                }
+                AuthenticationAgentEvent::LockedOut { cookie, until } => {
+                    if self.cookie.as_ref() == Some(cookie) {
+                        match until {
+                            Some(until) => {
+                                self.locked_until = Some(*until);
+                            }
+                            None => {
+                                self.retry_message = Some(gettext("This account is locked."));
+                            }
                         }
                     }
                 }
+                AuthenticationAgentEvent::IdleWarning { cookie, until } => {
+                    if self.cookie.as_ref() == Some(cookie) {
+                        self.idle_until = *until;
+                    }
+                }
+                AuthenticationAgentEvent::Info { cookie, message } => {
+                    if self.cookie.as_ref() == Some(cookie) {
+                        self.retry_message = Some(message.clone());
+                        // Info without a prompt means PAM waits for something, like a finger on
+                        // the reader or a touch of the security key.
+                        self.waiting = self.prompt.is_none() && !self.changing_password;
+                    }
+                }
+                AuthenticationAgentEvent::Error { cookie, message } => {
+                    if self.cookie.as_ref() == Some(cookie) {
+                        self.retry_message = Some(message.clone());
+                    }
+                }
             },
         }
+        self.schedule_tick(&sender);
+    }
+
+    async fn update_cmd_with_view(
+        &mut self,
+        widgets: &mut Self::Widgets,
+        message: Self::CommandOutput,
+        sender: AsyncComponentSender<Self>,
+        _root: &Self::Root,
+    ) {
+        self.identities_changed = false;
+        match message {
+            AppCommand::Dismiss(cookie) => {
+                if self.closing && self.cookie.as_ref() == Some(&cookie) {
+                    self.next_request().await;
+                }
+            }
+            AppCommand::Tick => {
+                self.ticking = false;
+                if self
+                    .locked_until
+                    .is_some_and(|until| Instant::now() >= until)
+                {
+                    tracing::debug!("lockout expired, allowing another attempt");
+                    self.locked_until = None;
+                    self.retry_message = None;
+                    // The helper already gave up on the locked attempt, so there is nothing to
+                    // answer until it is started again.
+                    if self.step.is_none() && !self.closing {
+                        self.failure_messages.clear();
+                        self.select_identity().await;
+                    }
+                }
+            }
+            AppCommand::ClearEntries => {
+                widgets.password_entry.set_text("");
+                widgets.response_entry.set_text("");
+                widgets.new_password_entry.set_text("");
+                widgets.confirm_password_entry.set_text("");
+            }
+        }
+        self.schedule_tick(&sender);
+        self.update_view(widgets, sender);
+    }
+}
+
+/// The dialog, styled with the user's `soteria/style.css` when they have one.
+#[derive(Debug)]
+pub struct GtkFrontend {
+    css_path: PathBuf,
+}
+
+impl GtkFrontend {
+    pub fn new() -> Result<Self> {
+        let config_path = std::env::var("XDG_CONFIG_HOME")
+            .or(std::env::var("HOME").map(|e| e + "/.config"))
+            .context("Could not resolve configuration path")?;
+
+        Ok(Self {
+            css_path: Path::new(&config_path).join("soteria/style.css"),
+        })
+    }
+}
+
+impl Frontend for GtkFrontend {
+    fn run(self: Box<Self>, channel: FrontendChannel) -> BoxFuture<'static, Result<()>> {
+        // GTK has to run on the main thread, so this blocks the thread main is awaiting on. The agent
+        // keeps running on tokio's workers meanwhile.
+        Box::pin(async move {
+            let app = RelmApp::new("gay.vaskel.soteria");
+            if self.css_path.is_file() {
+                tracing::info!("loading css stylesheet from {}", self.css_path.display());
+                relm4::set_global_css_from_file(&self.css_path)
+                    .context("Could not load CSS stylesheet for some reason")?;
+            }
+            app.run_async::<App>(channel);
+
+            Ok(())
+        })
     }
 }
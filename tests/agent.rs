@@ -0,0 +1,528 @@
+//! Runs soteria against a fake polkit on a private bus.
+//!
+//! Every test starts its own `dbus-daemon`, which stands in for both the system and the session
+//! bus. The test owns `org.freedesktop.PolicyKit1` on it and serves a fake Authority, then starts
+//! soteria with `--external` and a scripted helper. From there it plays both polkit, calling
+//! `BeginAuthentication` and `CancelAuthentication`, and the shell, answering through
+//! `gay.vaskel.Soteria.Prompt`.
+//!
+//! They need `dbus-daemon` installed and the `test-helper` feature, without which soteria refuses
+//! to run a helper that is not a root owned setuid binary.
+
+use std::{
+    collections::HashMap, os::unix::fs::PermissionsExt, path::PathBuf, process::Stdio,
+    time::Duration,
+};
+
+use futures_lite::StreamExt;
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    process::{Child, Command},
+    sync::mpsc,
+    task::JoinHandle,
+    time::timeout,
+};
+use zbus::{
+    MatchRule, Message, MessageStream, conn, interface,
+    message::{Header, Type},
+    proxy,
+    zvariant::{OwnedValue, Value},
+};
+
+const POLKIT_BUS_NAME: &str = "org.freedesktop.PolicyKit1";
+const AGENT_PATH: &str = "/org/freedesktop/PolicyKit1/AuthenticationAgent";
+const PROMPT_INTERFACE: &str = "gay.vaskel.Soteria.Prompt";
+const PASSWORD: &str = "hunter2";
+/// How long any single step may take before the test gives up.
+const STEP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Asks for a password and accepts only [`PASSWORD`].
+const PASSWORD_HELPER: &str = r#"#!/bin/sh
+read cookie
+echo "PAM_PROMPT_ECHO_OFF Password:"
+read password
+if [ "$password" = "hunter2" ]; then
+    echo SUCCESS
+else
+    echo "PAM_ERROR_MSG Authentication failure"
+    echo FAILURE
+fi
+"#;
+
+/// Asks for a password and then reports the account locked like pam_faillock does.
+const LOCKED_HELPER: &str = r#"#!/bin/sh
+read cookie
+echo "PAM_PROMPT_ECHO_OFF Password:"
+read password
+echo "PAM_ERROR_MSG The account is locked due to 3 failed logins."
+echo "PAM_TEXT_INFO (10 minutes left to unlock)"
+echo FAILURE
+"#;
+
+/// What soteria registered itself with.
+#[derive(Debug)]
+struct Registration {
+    sender: String,
+    kind: String,
+    session_id: Option<String>,
+    object_path: String,
+}
+
+struct FakeAuthority {
+    registrations: mpsc::UnboundedSender<Registration>,
+}
+
+#[interface(name = "org.freedesktop.PolicyKit1.Authority")]
+impl FakeAuthority {
+    async fn register_authentication_agent(
+        &self,
+        subject: (String, HashMap<String, OwnedValue>),
+        _locale: String,
+        object_path: String,
+        #[zbus(header)] header: Header<'_>,
+    ) {
+        let (kind, details) = subject;
+        let session_id = details
+            .get("session-id")
+            .and_then(|id| String::try_from(id.clone()).ok());
+        let _ = self.registrations.send(Registration {
+            sender: header.sender().map(|s| s.to_string()).unwrap_or_default(),
+            kind,
+            session_id,
+            object_path,
+        });
+    }
+
+    #[allow(clippy::type_complexity)]
+    async fn enumerate_actions(
+        &self,
+        _locale: String,
+    ) -> Vec<(
+        String,
+        String,
+        String,
+        String,
+        String,
+        String,
+        u32,
+        u32,
+        u32,
+        HashMap<String, String>,
+    )> {
+        Vec::new()
+    }
+
+    #[zbus(signal)]
+    async fn changed(emitter: &zbus::object_server::SignalEmitter<'_>) -> zbus::Result<()>;
+}
+
+#[proxy(
+    interface = "org.freedesktop.PolicyKit1.AuthenticationAgent",
+    default_path = "/org/freedesktop/PolicyKit1/AuthenticationAgent"
+)]
+trait Agent {
+    fn begin_authentication(
+        &self,
+        action_id: &str,
+        message: &str,
+        icon_name: &str,
+        details: HashMap<&str, &str>,
+        cookie: &str,
+        identities: &[(&str, HashMap<&str, Value<'_>>)],
+    ) -> zbus::Result<()>;
+
+    fn cancel_authentication(&self, cookie: &str) -> zbus::Result<()>;
+}
+
+#[proxy(
+    interface = "gay.vaskel.Soteria.Prompt",
+    default_service = "gay.vaskel.Soteria",
+    default_path = "/gay/vaskel/Soteria"
+)]
+trait Prompt {
+    fn select_identity(&self, cookie: &str, username: &str) -> zbus::Result<()>;
+
+    fn respond(&self, cookie: &str, step: u32, response: &str) -> zbus::Result<()>;
+}
+
+/// Cookie, message, identities as username, label and avatar, and details.
+type RequestStarted = (
+    String,
+    String,
+    Vec<(String, String, String)>,
+    HashMap<String, String>,
+);
+
+/// The signals of `gay.vaskel.Soteria.Prompt` the tests look at.
+#[derive(Debug)]
+enum Signal {
+    Started { usernames: Vec<String> },
+    Prompt { step: u32 },
+    Retry { messages: Vec<String> },
+    LockedOut { seconds: u64 },
+    Finished { outcome: String },
+    Other,
+}
+
+impl Signal {
+    fn parse(message: &Message) -> zbus::Result<(String, Self)> {
+        let header = message.header();
+        let member = header.member().map(|m| m.as_str()).unwrap_or_default();
+        let body = message.body();
+        let (cookie, signal) = match member {
+            "RequestStarted" => {
+                let (cookie, _, identities, _): RequestStarted = body.deserialize()?;
+                let usernames = identities.into_iter().map(|(name, _, _)| name).collect();
+                (cookie, Self::Started { usernames })
+            }
+            "Prompt" => {
+                let (cookie, step, _, _): (String, u32, String, bool) = body.deserialize()?;
+                (cookie, Self::Prompt { step })
+            }
+            "Retry" => {
+                let (cookie, _, messages): (String, String, Vec<String>) = body.deserialize()?;
+                (cookie, Self::Retry { messages })
+            }
+            "LockedOut" => {
+                let (cookie, seconds): (String, u64) = body.deserialize()?;
+                (cookie, Self::LockedOut { seconds })
+            }
+            "RequestFinished" => {
+                let (cookie, outcome): (String, String) = body.deserialize()?;
+                (cookie, Self::Finished { outcome })
+            }
+            _ => (String::new(), Self::Other),
+        };
+        Ok((cookie, signal))
+    }
+}
+
+/// A private bus with a fake polkit on it and soteria registered with that polkit.
+struct Harness {
+    dir: PathBuf,
+    address: String,
+    // Both are killed when the harness is dropped.
+    _bus: Child,
+    _soteria: Child,
+    agent: AgentProxy<'static>,
+    prompt: PromptProxy<'static>,
+    signals: MessageStream,
+}
+
+impl Harness {
+    /// Starts everything with `helper` as the authentication helper.
+    async fn start(name: &str, helper: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("soteria-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("config/soteria")).unwrap();
+
+        let helper_path = dir.join("helper");
+        std::fs::write(&helper_path, helper).unwrap();
+        std::fs::set_permissions(&helper_path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::fs::write(
+            dir.join("config/soteria/config.toml"),
+            format!(
+                "helper_path = \"{}\"\nsocket_path = \"{}\"\nmax_attempts = 3\nidle_timeout = 0\n",
+                helper_path.display(),
+                dir.join("missing.sock").display()
+            ),
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("bus.conf"),
+            format!(
+                r#"<!DOCTYPE busconfig PUBLIC "-//freedesktop//DTD D-Bus Bus Configuration 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/busconfig.dtd">
+<busconfig>
+  <type>session</type>
+  <listen>unix:path={}</listen>
+  <auth>EXTERNAL</auth>
+  <policy context="default">
+    <allow send_destination="*" eavesdrop="true"/>
+    <allow eavesdrop="true"/>
+    <allow own="*"/>
+  </policy>
+</busconfig>
+"#,
+                dir.join("bus").display()
+            ),
+        )
+        .unwrap();
+
+        let mut bus = Command::new("dbus-daemon")
+            .arg(format!("--config-file={}", dir.join("bus.conf").display()))
+            .args(["--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .expect("could not start dbus-daemon, is it installed?");
+        let mut address = String::new();
+        let mut stdout = BufReader::new(bus.stdout.take().unwrap());
+        timeout(STEP_TIMEOUT, stdout.read_line(&mut address))
+            .await
+            .expect("dbus-daemon did not print its address")
+            .unwrap();
+        let address = address.trim().to_string();
+
+        let (registrations, mut registered) = mpsc::unbounded_channel();
+        let connection = conn::Builder::address(address.as_str())
+            .unwrap()
+            .name(POLKIT_BUS_NAME)
+            .unwrap()
+            .serve_at(
+                "/org/freedesktop/PolicyKit1/Authority",
+                FakeAuthority { registrations },
+            )
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+        let rule = MatchRule::builder()
+            .msg_type(Type::Signal)
+            .interface(PROMPT_INTERFACE)
+            .unwrap()
+            .build();
+        let signals = MessageStream::for_match_rule(rule, &connection, None)
+            .await
+            .unwrap();
+
+        let log = std::fs::File::create(dir.join("soteria.log")).unwrap();
+        let soteria = Command::new(env!("CARGO_BIN_EXE_soteria"))
+            .arg("--external")
+            .env_clear()
+            .env("PATH", std::env::var_os("PATH").unwrap_or_default())
+            .env("DBUS_SYSTEM_BUS_ADDRESS", &address)
+            .env("DBUS_SESSION_BUS_ADDRESS", &address)
+            .env("XDG_CONFIG_HOME", dir.join("config"))
+            .env("XDG_SESSION_ID", "test-session")
+            .env("SOTERIA_ALLOW_UNSAFE_HELPER", "1")
+            .env("RUST_LOG", "debug")
+            .stdout(log.try_clone().unwrap())
+            .stderr(log)
+            .kill_on_drop(true)
+            .spawn()
+            .unwrap();
+
+        let registration = timeout(STEP_TIMEOUT, registered.recv())
+            .await
+            .expect("soteria did not register")
+            .unwrap();
+        assert_eq!(registration.kind, "unix-session");
+        assert_eq!(registration.session_id.as_deref(), Some("test-session"));
+        assert_eq!(registration.object_path, AGENT_PATH);
+
+        let agent = AgentProxy::builder(&connection)
+            .destination(registration.sender)
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+        let prompt = PromptProxy::new(&connection).await.unwrap();
+
+        Self {
+            dir,
+            address,
+            _bus: bus,
+            _soteria: soteria,
+            agent,
+            prompt,
+            signals,
+        }
+    }
+
+    /// Asks soteria to authenticate the user running the test, resolving once it answers.
+    fn begin(&self, cookie: &str) -> JoinHandle<zbus::Result<()>> {
+        let agent = self.agent.clone();
+        let cookie = cookie.to_string();
+        tokio::spawn(async move {
+            let uid = unsafe { libc::getuid() };
+            agent
+                .begin_authentication(
+                    "org.example.test",
+                    "Authentication is needed to run the tests",
+                    "",
+                    HashMap::new(),
+                    &cookie,
+                    &[("unix-user", HashMap::from([("uid", Value::from(uid))]))],
+                )
+                .await
+        })
+    }
+
+    /// The next signal for `cookie`, skipping everything else.
+    async fn next(&mut self, cookie: &str) -> Signal {
+        loop {
+            let message = timeout(STEP_TIMEOUT, self.signals.next())
+                .await
+                .expect("soteria stopped talking")
+                .unwrap()
+                .unwrap();
+            let (from, signal) = Signal::parse(&message).unwrap();
+            if from == cookie && !matches!(signal, Signal::Other) {
+                return signal;
+            }
+        }
+    }
+
+    /// Waits for the request to start and picks the first identity, as the shell would.
+    async fn select(&mut self, cookie: &str) {
+        let Signal::Started { usernames } = self.next(cookie).await else {
+            panic!("expected the request to start");
+        };
+        let username = usernames.first().expect("soteria offered nobody");
+        self.prompt.select_identity(cookie, username).await.unwrap();
+    }
+
+    /// Waits for the password prompt and answers it.
+    async fn answer(&mut self, cookie: &str, response: &str) {
+        let step = loop {
+            match self.next(cookie).await {
+                Signal::Prompt { step } => break step,
+                Signal::Retry { .. } | Signal::LockedOut { .. } => {}
+                signal => panic!("expected a prompt, got {signal:?}"),
+            }
+        };
+        self.prompt.respond(cookie, step, response).await.unwrap();
+    }
+
+    async fn finished(&mut self, cookie: &str) -> String {
+        loop {
+            if let Signal::Finished { outcome } = self.next(cookie).await {
+                return outcome;
+            }
+        }
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            if let Ok(log) = std::fs::read_to_string(self.dir.join("soteria.log")) {
+                eprintln!("soteria's log:\n{log}");
+            }
+        }
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+async fn result(begin: JoinHandle<zbus::Result<()>>) -> zbus::Result<()> {
+    timeout(STEP_TIMEOUT, begin)
+        .await
+        .expect("BeginAuthentication did not return")
+        .unwrap()
+}
+
+fn assert_polkit_error(result: zbus::Result<()>, name: &str) {
+    match result {
+        Err(zbus::Error::MethodError(error, _, _)) => assert_eq!(error.as_str(), name),
+        other => panic!("expected {name}, got {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn authenticates() {
+    let mut harness = Harness::start("authenticates", PASSWORD_HELPER).await;
+    let begin = harness.begin("cookie");
+    harness.select("cookie").await;
+    harness.answer("cookie", PASSWORD).await;
+
+    assert_eq!(harness.finished("cookie").await, "succeeded");
+    result(begin).await.unwrap();
+}
+
+#[tokio::test]
+async fn retries_after_a_wrong_password() {
+    let mut harness = Harness::start("retries", PASSWORD_HELPER).await;
+    let begin = harness.begin("cookie");
+    harness.select("cookie").await;
+    harness.answer("cookie", "wrong").await;
+
+    let Signal::Retry { messages } = harness.next("cookie").await else {
+        panic!("expected a retry");
+    };
+    assert_eq!(messages, ["Authentication failure"]);
+    harness.answer("cookie", PASSWORD).await;
+
+    assert_eq!(harness.finished("cookie").await, "succeeded");
+    result(begin).await.unwrap();
+}
+
+#[tokio::test]
+async fn gives_up_after_the_last_attempt() {
+    let mut harness = Harness::start("exhausted", PASSWORD_HELPER).await;
+    let begin = harness.begin("cookie");
+    harness.select("cookie").await;
+    for _ in 0..3 {
+        harness.answer("cookie", "wrong").await;
+    }
+
+    assert_eq!(harness.finished("cookie").await, "exhausted");
+    assert_polkit_error(
+        result(begin).await,
+        "org.freedesktop.PolicyKit1.Error.Cancelled",
+    );
+}
+
+#[tokio::test]
+async fn reports_a_lockout() {
+    let mut harness = Harness::start("lockout", LOCKED_HELPER).await;
+    let begin = harness.begin("cookie");
+    harness.select("cookie").await;
+    harness.answer("cookie", PASSWORD).await;
+
+    // pam_faillock says the account is locked before it says for how long.
+    let Signal::LockedOut { seconds: 0 } = harness.next("cookie").await else {
+        panic!("expected a lockout");
+    };
+    let Signal::LockedOut { seconds } = harness.next("cookie").await else {
+        panic!("expected how long the lockout lasts");
+    };
+    // Counted down from when soteria read it.
+    assert!((590..=600).contains(&seconds), "locked out for {seconds}s");
+
+    harness.agent.cancel_authentication("cookie").await.unwrap();
+    assert_eq!(harness.finished("cookie").await, "canceled");
+    assert_polkit_error(
+        result(begin).await,
+        "org.freedesktop.PolicyKit1.Error.Cancelled",
+    );
+}
+
+#[tokio::test]
+async fn cancels_when_polkit_does() {
+    let mut harness = Harness::start("cancel", PASSWORD_HELPER).await;
+    let begin = harness.begin("cookie");
+    harness.select("cookie").await;
+    let Signal::Prompt { .. } = harness.next("cookie").await else {
+        panic!("expected a prompt");
+    };
+
+    harness.agent.cancel_authentication("cookie").await.unwrap();
+    assert_eq!(harness.finished("cookie").await, "canceled");
+    assert_polkit_error(
+        result(begin).await,
+        "org.freedesktop.PolicyKit1.Error.Cancelled",
+    );
+}
+
+#[tokio::test]
+async fn only_answers_polkit() {
+    let harness = Harness::start("caller", PASSWORD_HELPER).await;
+    let stranger = conn::Builder::address(harness.address.as_str())
+        .unwrap()
+        .build()
+        .await
+        .unwrap();
+    let agent = AgentProxy::builder(&stranger)
+        .destination(harness.agent.inner().destination().to_owned())
+        .unwrap()
+        .build()
+        .await
+        .unwrap();
+
+    assert_polkit_error(
+        agent.cancel_authentication("cookie").await,
+        "org.freedesktop.PolicyKit1.Error.NotAuthorized",
+    );
+}